@@ -0,0 +1,78 @@
+// Measures how long the simulation takes without rendering anything.
+
+use std::time::{Duration, Instant};
+
+use bevy::{diagnostic::DiagnosticsPlugin, prelude::*};
+
+use crate::{
+    adjust_number_of_boids, avoid_walls, build_grid, build_kdtree, find_centroid, flock, fly,
+    handle_walls, keep_apart,
+    record::{record, Recorder},
+    register_force_diagnostics, steer_boids, Centroid, DebugDraw, KdTree, Parameters, RngResource,
+    SpatialGrid, SpawnCounter,
+};
+
+// How many frames to run before measuring, so everything is spawned and warmed up.
+const WARMUP_FRAMES: usize = 10;
+// How many frames to measure.
+const FRAMES: usize = 1000;
+
+pub fn run(params: Parameters, recorder: Option<Recorder>) {
+    let how_many = params.number_of_boids;
+
+    let mut app = App::new();
+    app.add_plugins((MinimalPlugins, DiagnosticsPlugin))
+        .insert_resource(RngResource::new(params.seed))
+        .insert_resource(params)
+        .init_resource::<DebugDraw>()
+        .init_resource::<SpatialGrid>()
+        .init_resource::<KdTree>()
+        .init_resource::<Centroid>()
+        .init_resource::<SpawnCounter>()
+        .add_systems(
+            Update,
+            (
+                adjust_number_of_boids,
+                build_grid,
+                build_kdtree,
+                find_centroid,
+                flock,
+                steer_boids,
+                avoid_walls,
+                handle_walls,
+                fly,
+                keep_apart,
+            )
+                .chain(),
+        );
+    register_force_diagnostics(&mut app);
+    if let Some(recorder) = recorder {
+        app.insert_resource(recorder)
+            .add_systems(PostUpdate, record.run_if(resource_exists::<Recorder>));
+    }
+    app.finish();
+    app.cleanup();
+
+    for _ in 0..WARMUP_FRAMES {
+        app.update();
+    }
+    let mut frame_times: Vec<Duration> = (0..FRAMES)
+        .map(|_| {
+            let start = Instant::now();
+            app.update();
+            start.elapsed()
+        })
+        .collect();
+    frame_times.sort();
+
+    let percentile = |p: usize| frame_times[(frame_times.len() - 1) * p / 100];
+    println!("{} boids, {} frames", how_many, FRAMES);
+    println!(
+        "average {:?}, p50 {:?}, p95 {:?}, p99 {:?}, max {:?}",
+        frame_times.iter().sum::<Duration>() / FRAMES as u32,
+        percentile(50),
+        percentile(95),
+        percentile(99),
+        percentile(100),
+    );
+}
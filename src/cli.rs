@@ -0,0 +1,130 @@
+// Command line flags, to start from a given configuration.
+
+use std::path::PathBuf;
+
+use clap::{Parser, ValueEnum};
+
+use crate::{BoundaryMode, Parameters, MAX_BOIDS};
+
+#[derive(Parser)]
+#[command(about = "Flocking birds, or boids")]
+pub struct Args {
+    /// Measure how long the simulation takes with this many boids, without opening a window
+    #[arg(long, value_name = "NUMBER OF BOIDS")]
+    pub bench: Option<usize>,
+
+    /// Fly the boids in 3D, inside a box as wide and high as the window
+    #[arg(long = "3d", conflicts_with = "bench")]
+    pub three_d: bool,
+
+    /// Draw the boids as sprites instead of meshes, which is faster with many boids but only has
+    /// the triangle shape
+    #[arg(long)]
+    pub sprites: bool,
+
+    /// Start from the obstacles and waypoints of a scenario file
+    #[arg(long, value_name = "PATH")]
+    pub scenario: Option<PathBuf>,
+
+    /// Write the trajectory of every boid to a CSV file
+    #[arg(long, value_name = "PATH")]
+    pub record: Option<PathBuf>,
+
+    /// Write statistics about the flock, like its average speed and polarization, to a JSON
+    /// lines file every frame
+    #[arg(long, value_name = "PATH")]
+    pub metrics: Option<PathBuf>,
+
+    /// Save the seed and parameters of the run to a replay file on exit
+    #[arg(long, value_name = "PATH")]
+    pub record_replay: Option<PathBuf>,
+
+    /// Replay a run saved with --record-replay, ignoring the other flags
+    #[arg(long, value_name = "PATH", conflicts_with_all = ["record_replay", "bench", "three_d"])]
+    pub replay: Option<PathBuf>,
+
+    /// How many boids to start with
+    #[arg(long, value_parser = clap::value_parser!(u64).range(1..=MAX_BOIDS as u64))]
+    boids: Option<u64>,
+
+    /// The seed of the random number generator, for reproducible runs
+    #[arg(long)]
+    seed: Option<u32>,
+
+    /// The width of the window, and of the world unless its size was set
+    #[arg(long, value_parser = parse_window_size)]
+    pub width: Option<f32>,
+
+    /// The height of the window, and of the world unless its size was set
+    #[arg(long, value_parser = parse_window_size)]
+    pub height: Option<f32>,
+
+    /// How far away each boid can see
+    #[arg(long, value_parser = parse_view_distance)]
+    view_distance: Option<f32>,
+
+    /// What happens to boids that reach the edge of the window
+    #[arg(long, value_enum)]
+    walls: Option<Walls>,
+
+    /// Bounce off the edges of the window, the same as --walls bounce
+    #[arg(long, conflicts_with = "walls")]
+    bounce: bool,
+}
+
+#[derive(Clone, Copy, ValueEnum)]
+enum Walls {
+    Bounce,
+    Wrap,
+    Mirror,
+    SoftAvoid,
+    Sticky,
+}
+
+impl Args {
+    // Overrides the parameters given on the command line.
+    pub fn apply(&self, params: &mut Parameters) {
+        if let Some(boids) = self.boids {
+            params.number_of_boids = boids as usize;
+        }
+        if let Some(seed) = self.seed {
+            params.seed = seed.into();
+        }
+        if let Some(view_distance) = self.view_distance {
+            params.view_distance = view_distance;
+            params.separation_distance = params.separation_distance.min(view_distance);
+            params.cohesion_distance = params.cohesion_distance.min(view_distance);
+            params.alignment_distance = params.alignment_distance.min(view_distance);
+        }
+        let walls = if self.bounce {
+            Some(Walls::Bounce)
+        } else {
+            self.walls
+        };
+        if let Some(walls) = walls {
+            params.boundary_mode = match walls {
+                Walls::Bounce => BoundaryMode::Bounce,
+                Walls::Wrap => BoundaryMode::Wrap,
+                Walls::Mirror => BoundaryMode::Mirror,
+                Walls::SoftAvoid => BoundaryMode::SoftAvoid,
+                Walls::Sticky => BoundaryMode::Sticky,
+            };
+        }
+    }
+}
+
+fn parse_in_range(s: &str, min: f32, max: f32) -> Result<f32, String> {
+    let value: f32 = s.parse().map_err(|e| format!("{}", e))?;
+    if !(min..=max).contains(&value) {
+        return Err(format!("{} is not in {}..={}", value, min, max));
+    }
+    Ok(value)
+}
+
+fn parse_window_size(s: &str) -> Result<f32, String> {
+    parse_in_range(s, 100.0, 8192.0)
+}
+
+fn parse_view_distance(s: &str) -> Result<f32, String> {
+    parse_in_range(s, 0.0, 500.0)
+}
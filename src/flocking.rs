@@ -0,0 +1,283 @@
+// The vector math behind flocking, kept free of any ECS types.
+
+use std::f32::consts::PI;
+
+use bevy::prelude::*;
+use serde::{Deserialize, Serialize};
+
+// The smallest distance considered between two boids, to avoid division by zero.
+pub const MIN_DISTANCE: f32 = 0.001;
+
+// The direction boids at the exact same position separate in, from the first towards the second.
+// It's derived from the entities so they reliably push apart in a stable direction.
+pub fn overlap_direction(e1: Entity, e2: Entity) -> Vec2 {
+    let (lo, hi) = if e1 < e2 { (e1, e2) } else { (e2, e1) };
+    let hash = lo.to_bits().wrapping_mul(0x9e37_79b9_7f4a_7c15) ^ hi.to_bits();
+    let angle = (hash >> 40) as f32 / (1u64 << 24) as f32 * 2.0 * PI;
+    let direction = Vec2::from_angle(angle);
+    if e1 < e2 {
+        direction
+    } else {
+        -direction
+    }
+}
+
+// Whether two boids see each other this step, for about `fidelity` of all pairs. Like
+// overlap_direction() it's derived from the entities, and from a seed that changes every step,
+// so both boids agree and the choice doesn't depend on the order the pairs are visited in.
+pub fn sees(seed: u64, e1: Entity, e2: Entity, fidelity: f32) -> bool {
+    if fidelity >= 1.0 {
+        return true;
+    }
+    let (lo, hi) = if e1 < e2 { (e1, e2) } else { (e2, e1) };
+    let hash = ((seed ^ lo.to_bits()).wrapping_mul(0x9e37_79b9_7f4a_7c15) ^ hi.to_bits())
+        .wrapping_mul(0xbf58_476d_1ce4_e5b9);
+    ((hash >> 40) as f32 / (1u64 << 24) as f32) < fidelity
+}
+
+// How separation falls off with distance, up to the separation distance.
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+pub enum SeparationCurve {
+    // 1 / distance^bias, which gets very strong very close.
+    InversePower,
+    // From 1 when touching down to 0 at the separation distance, whatever the bias.
+    Linear,
+    // e^(-bias * distance / separation distance), sharper the larger the bias.
+    Exponential,
+}
+
+// Seperation should be stronger for boids closer to each other. This is what to multiply the
+// offset between them by, so it's divided by the distance to leave only the falloff.
+pub fn separation_factor(distance: f32, range: f32, curve: SeparationCurve, bias: f32) -> f32 {
+    let distance = distance.max(MIN_DISTANCE);
+    let range = range.max(MIN_DISTANCE);
+    match curve {
+        SeparationCurve::InversePower => 1.0 / distance.powf(bias),
+        SeparationCurve::Linear => (1.0 - distance / range).max(0.0) / distance,
+        SeparationCurve::Exponential => (-bias * distance / range).exp() / distance,
+    }
+}
+
+// How much to scale the separation between two boids by, given how fast they're closing in on
+// each other, so that boids going the same way barely push each other apart while those on a
+// collision course push harder.
+//
+// With d the position of one boid relative to the other and v its velocity relative to the
+// other's, the distance between them changes at d·v / |d|, negative when they get closer. The
+// closing speed is the opposite of that, and dividing it by the sum of their speeds bounds it to
+// [-1, 1]: 1 when flying straight at each other, 0 when keeping their distance, like boids in an
+// aligned flock, and negative when moving apart, which counts as 0. The factor goes linearly from
+// 1 - bias at 0 to 1 + bias at 1, so it's always 1 when the bias is 0, and never negative for a
+// bias between 0 and 1.
+pub fn closing_factor(closing_speed: f32, speeds: f32, bias: f32) -> f32 {
+    let closing = if speeds > 0.0 {
+        (closing_speed / speeds).clamp(0.0, 1.0)
+    } else {
+        0.0
+    };
+    1.0 + bias * (2.0 * closing - 1.0)
+}
+
+// How much a boid aligns with another, given both their velocities.
+pub fn alignment_factor(velocity: Vec2, other_velocity: Vec2, bias: f32) -> f32 {
+    alignment_factor_3d(velocity.extend(0.0), other_velocity.extend(0.0), bias)
+}
+
+// The same as alignment_factor(), for boids flying in 3D.
+pub fn alignment_factor_3d(velocity: Vec3, other_velocity: Vec3, bias: f32) -> f32 {
+    // Cosine similarity between the two velocities: 1.0 if same, -1.0 if opposite, and neutral
+    // when either isn't going anywhere.
+    let lengths = velocity.length() * other_velocity.length();
+    let similarity = if lengths > 0.0 {
+        velocity.dot(other_velocity) / lengths
+    } else {
+        0.0
+    };
+    // When bias > 1, prefers boids already going in a similar drection.
+    // When bias < 1, prefers boids going in the opposite direction.
+    bias.powf(similarity) / if bias > 1.0 { bias } else { 1.0 / bias }
+}
+
+// How much influence another boid has, given both their weights. Larger boids have a stronger
+// influence.
+pub fn weight_ratio(weight: f32, other_weight: f32) -> f32 {
+    other_weight.powi(2) / weight.powi(2)
+}
+
+// The change in velocity from steering in a direction, limited by the steering force.
+pub fn steering(direction: Vec2, force: f32, steering_force: f32) -> Vec2 {
+    force * direction.clamp_length_max(steering_force)
+}
+
+// Applies the changes from steering to the velocity, keeping it within the speed limits.
+pub fn combine(velocity: Vec2, steering: &[Vec2], min_speed: f32, max_speed: f32) -> Vec2 {
+    let velocity = steering
+        .iter()
+        .fold(velocity, |velocity, steering| velocity + *steering);
+    // Without a heading there's no way to speed up to the minimum speed.
+    if velocity == Vec2::ZERO {
+        return velocity;
+    }
+    velocity.clamp_length(min_speed, max_speed)
+}
+
+// The corners of the smallest convex polygon around all the points, counter-clockwise. This is
+// Andrew's monotone chain: the lower and upper halves are built from the points sorted left to
+// right, dropping any corner that would turn the wrong way.
+pub fn convex_hull(mut points: Vec<Vec2>) -> Vec<Vec2> {
+    points.sort_unstable_by(|a, b| a.x.total_cmp(&b.x).then(a.y.total_cmp(&b.y)));
+    points.dedup();
+    if points.len() < 3 {
+        return points;
+    }
+
+    let half = |points: &mut dyn Iterator<Item = Vec2>| {
+        let mut half: Vec<Vec2> = Vec::new();
+        for p in points {
+            while let [.., a, b] = half[..] {
+                if (b - a).perp_dot(p - a) > 0.0 {
+                    break;
+                }
+                half.pop();
+            }
+            half.push(p);
+        }
+        // The last point starts the other half.
+        half.pop();
+        half
+    };
+    let mut hull = half(&mut points.iter().copied());
+    hull.extend(half(&mut points.iter().rev().copied()));
+    hull
+}
+
+// The area of a polygon given its corners in order, either way around.
+pub fn polygon_area(corners: &[Vec2]) -> f32 {
+    let doubled: f32 = corners
+        .iter()
+        .zip(corners.iter().cycle().skip(1))
+        .map(|(a, b)| a.perp_dot(*b))
+        .sum();
+    doubled.abs() / 2.0
+}
+
+// How aligned the velocities are, from 0 when they cancel out to 1 when they're all the same way.
+// This is the length of their average direction.
+pub fn polarization(velocities: impl Iterator<Item = Vec2>) -> f32 {
+    let (sum, count) = velocities.fold((Vec2::ZERO, 0), |(sum, count), velocity| {
+        (sum + velocity.normalize_or_zero(), count + 1)
+    });
+    if count == 0 {
+        return 0.0;
+    }
+    (sum / count as f32).length()
+}
+
+// How much the boids circle around the center, from 0 when they don't to 1 when they all go
+// around it the same way at the same distance. This is the normalized angular momentum about
+// the center, which is high when the flock mills around it.
+pub fn milling(boids: impl Iterator<Item = (Vec2, Vec2)>, center: Vec2) -> f32 {
+    let (sum, count) = boids.fold((0.0, 0), |(sum, count), (position, velocity)| {
+        let offset = (position - center).normalize_or_zero();
+        (
+            sum + offset.perp_dot(velocity.normalize_or_zero()),
+            count + 1,
+        )
+    });
+    if count == 0 {
+        return 0.0;
+    }
+    (sum / count as f32).abs()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Two boids 4 apart, the first going right and the second up, with forces picked so the
+    // results are exact in floating point.
+    const P1: Vec2 = Vec2::new(0.0, 0.0);
+    const P2: Vec2 = Vec2::new(0.0, 4.0);
+    const V1: Vec2 = Vec2::new(2.0, 0.0);
+    const V2: Vec2 = Vec2::new(0.0, 2.0);
+    const STEERING_FORCE: f32 = 10.0;
+
+    #[test]
+    fn cohesion_steers_towards_the_other_boid() {
+        assert_eq!(steering(P2 - P1, 2.0, STEERING_FORCE), Vec2::new(0.0, 8.0));
+    }
+
+    #[test]
+    fn steering_is_limited_by_the_steering_force() {
+        assert_eq!(
+            steering(Vec2::new(0.0, 40.0), 1.0, STEERING_FORCE),
+            Vec2::new(0.0, 10.0)
+        );
+    }
+
+    #[test]
+    fn separation_steers_away_from_the_other_boid() {
+        let offset = P1 - P2;
+        let distance = offset.length();
+        let linear = separation_factor(distance, 8.0, SeparationCurve::Linear, 1.0);
+        assert_eq!(
+            steering(offset * linear, 1.0, STEERING_FORCE),
+            Vec2::new(0.0, -0.5)
+        );
+        let inverse = separation_factor(distance, 8.0, SeparationCurve::InversePower, 1.0);
+        assert_eq!(
+            steering(offset * inverse, 1.0, STEERING_FORCE),
+            Vec2::new(0.0, -1.0)
+        );
+        let exponential = separation_factor(distance, 8.0, SeparationCurve::Exponential, 0.0);
+        assert_eq!(
+            steering(offset * exponential, 1.0, STEERING_FORCE),
+            Vec2::new(0.0, -1.0)
+        );
+    }
+
+    #[test]
+    fn alignment_steers_towards_the_other_heading() {
+        // Perpendicular velocities are neither similar nor opposite.
+        let factor = alignment_factor(V1, V2, 2.0);
+        assert_eq!(factor, 0.5);
+        assert_eq!(
+            steering(V2 * factor, 1.0, STEERING_FORCE),
+            Vec2::new(0.0, 1.0)
+        );
+        assert_eq!(alignment_factor(V1, V1, 2.0), 1.0);
+        assert_eq!(alignment_factor(V1, -V1, 2.0), 0.25);
+        assert_eq!(alignment_factor(V1, Vec2::ZERO, 2.0), 0.5);
+    }
+
+    #[test]
+    fn combine_adds_up_the_forces() {
+        let forces = [
+            Vec2::new(0.0, 8.0),
+            Vec2::new(0.0, -0.5),
+            Vec2::new(0.0, 1.0),
+        ];
+        assert_eq!(combine(V1, &forces, 0.0, 100.0), Vec2::new(2.0, 8.5));
+        assert_eq!(combine(V1, &forces, 0.0, 0.5).length(), 0.5);
+    }
+
+    #[test]
+    fn overlapping_boids_separate_in_opposite_directions() {
+        let (e1, e2) = (Entity::from_raw(1), Entity::from_raw(2));
+        let direction = overlap_direction(e1, e2);
+        assert!(direction.is_finite());
+        assert!((direction.length() - 1.0).abs() < 1e-6);
+        assert_eq!(overlap_direction(e1, e2), direction);
+        assert_eq!(overlap_direction(e2, e1), -direction);
+    }
+
+    #[test]
+    fn boids_standing_still_without_steering_stay_still() {
+        assert_eq!(
+            combine(Vec2::ZERO, &[Vec2::ZERO; 3], 25.0, 250.0),
+            Vec2::ZERO
+        );
+        let velocity = combine(Vec2::ZERO, &[Vec2::X], 25.0, 250.0);
+        assert_eq!(velocity, Vec2::new(25.0, 0.0));
+    }
+}
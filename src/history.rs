@@ -0,0 +1,119 @@
+// Keeping the recent states of the boids, so the simulation can be rewound while paused.
+
+use std::collections::VecDeque;
+
+use bevy::prelude::*;
+
+use crate::{Boid, Interpolated, Parameters, Trail};
+
+// The position and velocity of each boid at one simulation step.
+type Frame = Vec<(Entity, Vec2, Vec2)>;
+
+#[derive(Resource, Default)]
+pub struct History {
+    // The oldest frame first.
+    frames: VecDeque<Frame>,
+    steps: u64,
+    // The frame the boids were rewound to, if any. Stepping forward from it forgets the frames
+    // that came after it.
+    shown: Option<usize>,
+    restore: bool,
+}
+
+impl History {
+    pub fn len(&self) -> usize {
+        self.frames.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.frames.is_empty()
+    }
+
+    // How much simulated time the frames cover, in seconds.
+    pub fn span(&self, params: &Parameters) -> f32 {
+        (self.frames.len() * params.history_interval as usize) as f32 / params.simulation_hz
+    }
+
+    // Roughly how much memory the frames use, in bytes.
+    pub fn size(&self) -> usize {
+        self.frames
+            .iter()
+            .map(|frame| frame.capacity() * std::mem::size_of::<(Entity, Vec2, Vec2)>())
+            .sum()
+    }
+
+    // The frame being shown, the latest one unless rewound.
+    pub fn shown(&self) -> usize {
+        self.shown.unwrap_or(self.frames.len().saturating_sub(1))
+    }
+
+    // Asks for the boids to be put back to the given frame, see rewind().
+    pub fn show(&mut self, index: usize) {
+        self.shown = Some(index.min(self.frames.len().saturating_sub(1)));
+        self.restore = true;
+    }
+}
+
+// Saves the positions and velocities of the boids every few steps, once they've moved.
+pub fn record(
+    params: Res<Parameters>,
+    mut history: ResMut<History>,
+    query: Query<(Entity, &Transform, &Boid)>,
+) {
+    let history = &mut *history;
+    if let Some(shown) = history.shown.take() {
+        history.frames.truncate(shown + 1);
+    }
+    history.steps += 1;
+    if params.history_length == 0 {
+        history.frames.clear();
+        return;
+    }
+    if !history
+        .steps
+        .is_multiple_of(params.history_interval.max(1) as u64)
+    {
+        return;
+    }
+
+    // Reuse the allocation of the oldest frame once the buffer is full.
+    let mut frame = Frame::new();
+    while history.frames.len() >= params.history_length {
+        frame = history.frames.pop_front().unwrap_or_default();
+    }
+    frame.clear();
+    frame.extend(
+        query
+            .iter()
+            .map(|(e, t, boid)| (e, t.translation.truncate(), boid.velocity)),
+    );
+    history.frames.push_back(frame);
+}
+
+// Puts the boids back where they were in the frame picked on the timeline. Boids spawned since
+// then are left where they are.
+pub fn rewind(
+    mut history: ResMut<History>,
+    mut query: Query<(&mut Transform, &mut Boid, &mut Interpolated, &mut Trail)>,
+) {
+    if !history.restore {
+        return;
+    }
+    history.restore = false;
+    let Some(frame) = history.shown.and_then(|shown| history.frames.get(shown)) else {
+        return;
+    };
+
+    for &(entity, position, velocity) in frame {
+        let Ok((mut t, mut boid, mut interpolated, mut trail)) = query.get_mut(entity) else {
+            continue; // Despawned since.
+        };
+        t.translation = position.extend(t.translation.z);
+        if velocity != Vec2::ZERO {
+            t.rotation = Quat::from_rotation_arc_2d(Vec2::Y, velocity.normalize());
+        }
+        boid.velocity = velocity;
+        *interpolated = Interpolated::new(*t);
+        trail.positions.clear();
+    }
+}
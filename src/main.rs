@@ -1,467 +1,6088 @@
-use std::{cmp::Ordering, f32::consts::PI, ops::Range};
+#[cfg(not(target_arch = "wasm32"))]
+mod bench;
+#[cfg(not(target_arch = "wasm32"))]
+mod cli;
+mod flocking;
+mod history;
+#[cfg(not(target_arch = "wasm32"))]
+mod metrics;
+#[cfg(not(target_arch = "wasm32"))]
+mod presets;
+#[cfg(not(target_arch = "wasm32"))]
+mod record;
+#[cfg(not(target_arch = "wasm32"))]
+mod replay;
+mod scenario;
+#[cfg(not(target_arch = "wasm32"))]
+mod scenes;
+#[cfg(not(target_arch = "wasm32"))]
+mod three_d;
+
+use std::{
+    cmp::Ordering,
+    collections::VecDeque,
+    f32::consts::PI,
+    ops::{Range, RangeInclusive},
+};
 
 use bevy::{
+    diagnostic::{
+        Diagnostic, DiagnosticPath, Diagnostics, DiagnosticsStore, FrameTimeDiagnosticsPlugin,
+        RegisterDiagnostic,
+    },
+    input::mouse::{MouseMotion, MouseScrollUnit, MouseWheel},
     prelude::*,
-    sprite::{MaterialMesh2dBundle, Mesh2dHandle},
-    window::{PrimaryWindow, WindowResized},
+    render::{
+        camera::{ScalingMode, Viewport},
+        mesh::PrimitiveTopology,
+        render_asset::RenderAssetUsages,
+        render_resource::{Extent3d, TextureDimension, TextureFormat},
+    },
+    sprite::{Anchor, MaterialMesh2dBundle, Mesh2dHandle},
+    tasks::{block_on, futures_lite::future, AsyncComputeTaskPool, Task},
+    utils::{HashMap, Instant},
+    window::{PresentMode, PrimaryWindow},
 };
 
 use bevy_egui::{egui, EguiContexts, EguiPlugin};
-use rand::{thread_rng, Rng};
-use rand_distr::{Distribution, Exp};
+use egui_plot::{Legend, Line, Plot, PlotPoints};
+use flocking::{overlap_direction, SeparationCurve, MIN_DISTANCE};
+use history::History;
+use rand::{rngs::StdRng, thread_rng, Rng, SeedableRng};
+use rand_distr::{Distribution, Exp, Normal};
+use scenario::Scenario;
+use serde::{Deserialize, Serialize};
 
-#[derive(Resource)]
+// What happens to boids that reach the edge of the window.
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+enum BoundaryMode {
+    // Turn around and head back into the window.
+    Bounce,
+    // Reappear on the opposite edge, as if the window was a torus.
+    Wrap,
+    // Jump to the mirrored position on the other side of the window.
+    Mirror,
+    // Gradually turn away when getting close to the edge, bouncing only as a last resort.
+    SoftAvoid,
+    // Land on the edge and rest there for a while, then take off back into the window. Predators
+    // bounce instead.
+    Sticky,
+}
+
+// What the color of the boids shows.
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+enum ColorMode {
+    // Which direction they're going in, or their species when there are several.
+    Heading,
+    // How fast they're going.
+    Speed,
+    // How many neighbours they have.
+    Neighbours,
+}
+
+// Where boids start from when the whole flock is spawned.
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+enum SpawnPattern {
+    // Anywhere in the window, going any which way.
+    Uniform,
+    // In a tight blob in the middle of the window.
+    Cluster,
+    // On a circle, going around it.
+    Ring,
+    // On a lattice covering the window, all heading the same way.
+    Grid,
+}
+
+// How leaders move, see lead().
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+enum LeaderPath {
+    // Weaving from side to side.
+    Wave,
+    // Towards the mouse pointer.
+    Mouse,
+}
+
+// What boids align with.
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+enum AlignmentMode {
+    // The velocities of their neighbours, so faster ones have more influence.
+    SummedVelocity,
+    // The headings of their neighbours, whatever their speed.
+    AverageHeading,
+}
+
+// What the boids look like.
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+enum BoidShape {
+    Triangle,
+    // A thinner and longer triangle, which shows the heading more clearly.
+    Arrow,
+    // For when the heading doesn't matter, like when looking at the density.
+    Circle,
+}
+
+// How a species of boids flocks and how fast it flies.
+#[derive(Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+struct SpeciesParams {
+    cohesion_force: f32,
+    separation_force: f32,
+    alignment_force: f32,
+    min_speed: f32,
+    max_speed: f32,
+}
+
+impl Default for SpeciesParams {
+    fn default() -> Self {
+        Self {
+            cohesion_force: 4.8,
+            separation_force: 2.2,
+            alignment_force: 6.8,
+            min_speed: 25.0,
+            max_speed: 250.0,
+        }
+    }
+}
+
+// Missing fields take their default value, so presets saved before a parameter was added still
+// load.
+#[derive(Resource, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
 struct Parameters {
-    window_width: f32,
-    window_height: f32,
+    // The size of the area the boids fly in. It follows the size of the window when fitting the
+    // world to it, otherwise the camera zooms to show all of it whatever the window.
+    world_width: f32,
+    world_height: f32,
+    fit_world_to_window: bool,
     number_of_boids: usize,
+    number_of_species: usize,
     view_distance: f32,
+    // How close other boids have to be to keep away from them. No further than view_distance.
+    separation_distance: f32,
+    // How close other boids of the same species have to be to head towards them, and to align
+    // with them. No further than view_distance either.
+    cohesion_distance: f32,
+    alignment_distance: f32,
+    // How fast both boids have to go to align with each other. Slower ones barely have a heading,
+    // so the way they're going is mostly noise.
+    alignment_min_speed: f32,
+    // How close boids can get to each other at all, or 0 for as close as they like.
+    min_distance: f32,
+    fov_degrees: f32,
 
-    cohesion_force: f32,
-    separation_force: f32,
+    // One for each possible species, whether it's in use or not.
+    species: Vec<SpeciesParams>,
+    // Whether heavier boids pull the others towards them harder, like they push and align them.
+    weighted_cohesion: bool,
+    // How far ahead to aim for where neighbours will be instead of where they are, in seconds.
+    prediction_time: f32,
+    separation_curve: SeparationCurve,
     separation_bias: f32,
+    // How much more boids closing in on each other keep apart than those going the same way, from
+    // 0 for no difference to 1, see flocking::closing_factor().
+    separation_velocity_bias: f32,
     alignment_bias: f32,
-    alignment_force: f32,
+    alignment_mode: AlignmentMode,
     steering_force: f32,
+    // How fast boids can turn, in degrees per second.
+    max_turn_rate: f32,
+    // How much the speed limits scale with weight: heavier boids are faster above 0 and slower
+    // below 0.
+    weight_speed_coupling: f32,
+    wander_strength: f32,
+    // How many of the closest boids of the same species each boid flocks with, or 0 for all of
+    // those in view.
+    max_neighbours: usize,
+
+    // How the parameters are laid out: in a panel beside the boids or a window over them, and
+    // with the forces in a table instead of sliders.
+    dock_parameters: bool,
+    compact_parameters: bool,
+
+    // How many simulation steps to take per second, regardless of the frame rate.
+    simulation_hz: f32,
+    // How much faster than real time the simulation runs.
+    time_scale: f32,
+    // How far boids move for their velocity. Unlike the time scale, the forces on them are
+    // applied at the same rate, so they keep flocking the same way, just covering more or less
+    // ground.
+    speed_multiplier: f32,
+    // Whether to wait for the display before showing each frame, and how many frames to show per
+    // second at most otherwise, or 0 for as many as possible.
+    vsync: bool,
+    max_frame_rate: f32,
+    // Whether to log the frame rate and the other diagnostics to the console every second.
+    log_diagnostics: bool,
 
     fidelity: f32,
 
-    min_speed: f32,
-    max_speed: f32,
+    // How many frames of history to keep for rewinding, and how many simulation steps apart.
+    history_length: usize,
+    history_interval: u32,
+
+    boundary_mode: BoundaryMode,
+    wall_margin: f32,
+    wall_turn_force: f32,
+    // How long boids rest on the edge with sticky walls, in seconds, picked at random in between.
+    min_perch_time: f32,
+    max_perch_time: f32,
+    // Whether to keep the center of the flock in the middle of the window, moving all the boids
+    // together as if the camera followed them.
+    lock_centroid: bool,
+
+    seed: u64,
+
+    background_flocking: bool,
+    parallel_flocking: bool,
+    use_kdtree: bool,
+
+    trail_length: usize,
+    // Whether to draw all the trails as a single mesh, instead of line by line.
+    trail_mesh: bool,
+    // Whether the boids leave their paths on a long exposure of the flock, see expose(), and how
+    // much of their brightness the paths lose every second.
+    exposure: bool,
+    exposure_fade: f32,
+    // Whether to recolor the boids as they fly, or to keep the hue they were spawned with.
+    recolor_by_heading: bool,
+    color_mode: ColorMode,
+    boid_shape: BoidShape,
+    spawn_pattern: SpawnPattern,
+    // How much boids spawned without a heading start off going the same way, from 0 for each its
+    // own way to 1 for all the same way.
+    initial_alignment: f32,
+    background: Color,
+    boid_saturation: f32,
+    boid_lightness: f32,
+    // Hex colors to give the boids in turn instead of the rainbow, separated by commas or spaces.
+    palette: String,
+
+    wind: Vec2,
+    wind_gusts: bool,
+
+    // How much energy boids and predators lose per second at full speed, and regain per second
+    // when stopped. They slow down as they tire.
+    energy_drain: f32,
+    energy_regen: f32,
+    // How old boids get before they're replaced by new ones, in seconds, or 0 for never. The new
+    // ones appear anywhere, like spawned boids, or come in from an edge.
+    max_age: f32,
+    respawn_at_edges: bool,
+
+    // How far the mouse reaches, and how strongly clicking draws boids in or pushes them away.
+    mouse_radius: f32,
+    mouse_attract_force: f32,
+    mouse_repel_force: f32,
+    // How strongly boids follow the pointer in the follow tool mode.
+    follow_force: f32,
+    // How far attractor and repeller points reach, and how strongly new ones pull or push.
+    attractor_radius: f32,
+    attractor_strength: f32,
+
+    // How far and how hard startling the boids pushes them away.
+    explode_radius: f32,
+    explode_strength: f32,
 
-    bounce_off_walls: bool,
+    gravity_enabled: bool,
+    gravity_strength: f32,
+    gravity_inverse_square: bool,
+    gravity_center: Vec2,
+
+    waypoint_force: f32,
+
+    cohesion_to_center: f32,
+    center_radius: f32,
+
+    obstacle_shape: ObstacleShape,
+    obstacle_radius: f32,
+    obstacle_width: f32,
+    obstacle_height: f32,
+    obstacle_force: f32,
+    // How far ahead boids look for obstacles in their way, in seconds.
+    lookahead_time: f32,
+
+    number_of_predators: usize,
+
+    // How many boids lead the others instead of flocking, how they move, and how much more than
+    // other boids they count towards cohesion.
+    number_of_leaders: usize,
+    leader_path: LeaderPath,
+    leader_weight: f32,
+    predator_fear_force: f32,
+    predator_speed: f32,
 }
 
 impl Default for Parameters {
     fn default() -> Self {
         Self {
-            window_width: 100.0,
-            window_height: 100.0,
+            world_width: 100.0,
+            world_height: 100.0,
+            fit_world_to_window: true,
             number_of_boids: 256,
+            number_of_species: 1,
             view_distance: 60.0,
-            cohesion_force: 4.8,
-            separation_force: 2.2,
+            separation_distance: 60.0,
+            cohesion_distance: 60.0,
+            alignment_distance: 60.0,
+            alignment_min_speed: 1.0,
+            min_distance: 0.0,
+            fov_degrees: 360.0,
+            species: vec![SpeciesParams::default(); SPECIES_HUES.len()],
+            weighted_cohesion: false,
+            prediction_time: 0.0,
+            separation_curve: SeparationCurve::InversePower,
             separation_bias: 1.1,
-            alignment_force: 6.8,
+            separation_velocity_bias: 0.0,
             alignment_bias: 1.0,
+            alignment_mode: AlignmentMode::SummedVelocity,
             steering_force: 1.0,
+            max_turn_rate: 3600.0,
+            weight_speed_coupling: 0.0,
+            wander_strength: 0.0,
+            max_neighbours: 0,
+            dock_parameters: false,
+            compact_parameters: false,
+            simulation_hz: 60.0,
+            history_length: 600,
+            history_interval: 1,
+            time_scale: 1.0,
+            speed_multiplier: 1.0,
+            vsync: true,
+            max_frame_rate: 0.0,
+            log_diagnostics: cfg!(debug_assertions),
             fidelity: 0.7,
-            min_speed: 25.0,
-            max_speed: 250.0,
-            bounce_off_walls: true,
+            boundary_mode: BoundaryMode::Bounce,
+            wall_margin: 50.0,
+            wall_turn_force: 10.0,
+            min_perch_time: 1.0,
+            max_perch_time: 5.0,
+            lock_centroid: false,
+            seed: random_seed(),
+            background_flocking: false,
+            parallel_flocking: true,
+            use_kdtree: false,
+            trail_length: 0,
+            trail_mesh: true,
+            exposure: false,
+            exposure_fade: 0.3,
+            recolor_by_heading: true,
+            color_mode: ColorMode::Heading,
+            boid_shape: BoidShape::Triangle,
+            spawn_pattern: SpawnPattern::Uniform,
+            initial_alignment: 0.0,
+            background: ClearColor::default().0,
+            boid_saturation: 0.95,
+            boid_lightness: 0.7,
+            palette: String::new(),
+            wind: Vec2::ZERO,
+            wind_gusts: false,
+            energy_drain: 0.0,
+            energy_regen: 0.2,
+            max_age: 0.0,
+            respawn_at_edges: false,
+            mouse_radius: 240.0,
+            mouse_attract_force: 4.8,
+            mouse_repel_force: 4.8,
+            attractor_radius: 150.0,
+            attractor_strength: 5.0,
+            follow_force: 0.5,
+            explode_radius: 200.0,
+            explode_strength: 500.0,
+            gravity_enabled: false,
+            gravity_strength: 100.0,
+            gravity_inverse_square: false,
+            gravity_center: Vec2::ZERO,
+            waypoint_force: 2.0,
+            cohesion_to_center: 0.0,
+            center_radius: 200.0,
+            obstacle_shape: ObstacleShape::Circle,
+            obstacle_radius: 30.0,
+            obstacle_width: 200.0,
+            obstacle_height: 20.0,
+            obstacle_force: 20.0,
+            lookahead_time: 0.5,
+            number_of_predators: 0,
+            number_of_leaders: 0,
+            leader_path: LeaderPath::Wave,
+            leader_weight: 10.0,
+            predator_fear_force: 30.0,
+            predator_speed: 200.0,
         }
     }
 }
 
+// A seed small enough to type, and to save in TOML which only has signed integers.
+fn random_seed() -> u64 {
+    thread_rng().gen::<u32>().into()
+}
+
 impl Parameters {
-    // The valid range of x, y coordinates given the window size.
-    fn window_x_range(&self) -> Range<f32> {
-        -self.window_width / 2.0..self.window_width / 2.0
+    fn species(&self, species: u8) -> &SpeciesParams {
+        &self.species[species as usize]
+    }
+
+    // The minimum and maximum speed of a boid, given its species and weight.
+    fn speed_limits(&self, boid: &Boid) -> (f32, f32) {
+        let species = self.species(boid.species);
+        let scale = boid.weight.powf(self.weight_speed_coupling);
+        let min_speed = species.min_speed * scale;
+        (
+            min_speed,
+            (species.max_speed * scale * stamina(boid.energy)).max(min_speed),
+        )
     }
-    fn window_y_range(&self) -> Range<f32> {
-        -self.window_height / 2.0..self.window_height / 2.0
+
+    // The valid range of x, y coordinates given the world size.
+    fn world_x_range(&self) -> Range<f32> {
+        -self.world_width / 2.0..self.world_width / 2.0
+    }
+    fn world_y_range(&self) -> Range<f32> {
+        -self.world_height / 2.0..self.world_height / 2.0
+    }
+
+    fn world_size(&self) -> Vec2 {
+        Vec2::new(self.world_width, self.world_height)
+    }
+
+    // The shortest vector from one position to another. When wrapping around the edges, this may
+    // cross an edge.
+    fn offset(&self, from: Vec2, to: Vec2) -> Vec2 {
+        let offset = to - from;
+        if self.boundary_mode != BoundaryMode::Wrap {
+            return offset;
+        }
+        let size = self.world_size();
+        offset - size * (offset / size).round()
+    }
+
+    // The positions around which to look for neighbours within the given radius: the position
+    // itself and, when wrapping around the edges, its images on the other side of the edges within
+    // the radius.
+    fn neighbourhood(&self, position: Vec2, radius: f32) -> Vec<Vec2> {
+        if self.boundary_mode != BoundaryMode::Wrap {
+            return vec![position];
+        }
+        let size = self.world_size();
+        let images = |p: f32, size: f32| {
+            let mut images = vec![0.0];
+            if p - radius < -size / 2.0 {
+                images.push(size);
+            }
+            if p + radius > size / 2.0 {
+                images.push(-size);
+            }
+            images
+        };
+        let xs = images(position.x, size.x);
+        let ys = images(position.y, size.y);
+        xs.iter()
+            .flat_map(|&x| ys.iter().map(move |&y| position + Vec2::new(x, y)))
+            .collect()
     }
 
-    // The maximum position vector given the window size.
+    // The maximum position vector given the world size.
     fn max_position(&self) -> Vec3 {
-        Vec3::new(self.window_width / 2.0, self.window_width / 2.0, 0.)
+        Vec3::new(self.world_width / 2.0, self.world_height / 2.0, 0.)
     }
-    // The minimum position vector given the window size.
+    // The minimum position vector given the world size.
     fn min_position(&self) -> Vec3 {
-        Vec3::new(-self.window_height / 2.0, -self.window_height / 2.0, 0.)
+        Vec3::new(-self.world_width / 2.0, -self.world_height / 2.0, 0.)
+    }
+
+    // Keeps the number of species in the range the slider allows, with parameters for each
+    // possible species, as loaded parameters may have been edited or saved by an older version.
+    fn fix_species(&mut self) {
+        self.number_of_species = self.number_of_species.clamp(1, SPECIES_HUES.len());
+        self.species
+            .resize(SPECIES_HUES.len(), SpeciesParams::default());
+    }
+
+    // Keeps the world size of the current parameters when fitting the world to the window, as it
+    // depends on the window rather than on the parameters then. A fixed world size is kept as is.
+    fn keep_fitted_world(&mut self, current: &Parameters) {
+        if self.fit_world_to_window {
+            self.world_width = current.world_width;
+            self.world_height = current.world_height;
+        }
+    }
+
+    // Switches to one of the built-in presets, keeping the world size, number of boids and seed.
+    fn apply_builtin_preset(&mut self, preset: usize) {
+        let mut params = Parameters {
+            world_width: self.world_width,
+            world_height: self.world_height,
+            fit_world_to_window: self.fit_world_to_window,
+            number_of_boids: self.number_of_boids,
+            seed: self.seed,
+            ..default()
+        };
+        match preset {
+            // Tight flocks.
+            1 => {
+                for species in &mut params.species {
+                    species.cohesion_force = 20.0;
+                    species.separation_force = 4.0;
+                    species.alignment_force = 4.0;
+                }
+            }
+            // A loose, restless swarm.
+            2 => {
+                params.view_distance = 40.0;
+                params.wander_strength = 0.1;
+                for species in &mut params.species {
+                    species.cohesion_force = 2.0;
+                    species.alignment_force = 0.5;
+                    species.min_speed = 50.0;
+                    species.max_speed = 300.0;
+                }
+            }
+            // Fish-like schools all heading the same way.
+            3 => {
+                params.fov_degrees = 270.0;
+                for species in &mut params.species {
+                    species.cohesion_force = 2.0;
+                    species.separation_force = 3.0;
+                    species.alignment_force = 30.0;
+                }
+            }
+            _ => (),
+        }
+        *self = params;
+    }
+
+    // Samples the forces, biases and speeds from the ranges of their sliders. Changing the number
+    // of boids respawns them, so it's only randomized when asked for.
+    fn randomize(&mut self, rng: &mut impl Rng, number_of_boids: bool) {
+        // Sliders with a logarithmic scale are sampled on that scale too.
+        let mut log_uniform =
+            |range: RangeInclusive<f32>| rng.gen_range(range.start().ln()..=range.end().ln()).exp();
+        if number_of_boids {
+            self.number_of_boids = log_uniform(8.0..=2048.0).round() as usize;
+        }
+        self.separation_bias = log_uniform(0.01..=10.0);
+        self.alignment_bias = log_uniform(0.01..=100.0);
+        self.steering_force = log_uniform(0.1..=100.0);
+        for species in &mut self.species {
+            species.cohesion_force = log_uniform(0.1..=100.0);
+            species.separation_force = log_uniform(0.1..=100.0);
+            species.alignment_force = log_uniform(0.1..=100.0);
+        }
+        self.view_distance = rng.gen_range(0.0..=500.0);
+        self.separation_distance = rng.gen_range(0.0..=self.view_distance);
+        self.fov_degrees = rng.gen_range(30.0..=360.0);
+        self.fidelity = rng.gen_range(0.01..=1.0);
+        for species in &mut self.species {
+            let (a, b) = (rng.gen_range(10.0..=500.0), rng.gen_range(10.0..=500.0));
+            species.min_speed = f32::min(a, b);
+            species.max_speed = f32::max(a, b);
+        }
     }
 }
 
-#[derive(Component, Debug)]
+#[derive(Component, Clone, Debug)]
 struct Boid {
     velocity: Vec2,
     weight: f32,
+    species: u8,
+    // From 0 when exhausted to 1 when fully rested, see tire().
+    energy: f32,
+    // How long it's been flying, in seconds, see grow_old().
+    age: f32,
+    // The heading it turns towards while it has no neighbours, in radians, see wander().
+    wander_angle: f32,
 }
 
 impl Boid {
-    fn new(x: f32, y: f32, w: f32) -> Self {
+    fn new(x: f32, y: f32, w: f32, species: u8) -> Self {
         Self {
             velocity: Vec2::new(x, y),
             weight: w,
+            species,
+            energy: 1.0,
+            age: 0.0,
+            wander_angle: Vec2::new(x, y).to_angle(),
         }
     }
 }
 
+// The colors of a palette like "#1b9e77, #d95f02, #7570b3", or what's wrong with it.
+fn parse_palette(palette: &str) -> Result<Vec<Color>, String> {
+    palette
+        .split(|c: char| c == ',' || c.is_whitespace())
+        .filter(|hex| !hex.is_empty())
+        .map(|hex| Color::hex(hex).map_err(|e| format!("{}: {}", hex, e)))
+        .collect()
+}
+
+// The hue of each species, when there's more than one.
+const SPECIES_HUES: [f32; 8] = [0., 210., 120., 50., 280., 180., 320., 25.];
+
 #[derive(Component, Default)]
 struct Calculations {
     neighbours: i32,
+    // The neighbours within the cohesion distance, and the sum of their weight ratios.
+    cohesion_neighbours: i32,
+    weights: f32,
+    // The sum of where those neighbours are, relative to the boid.
     cohesion: Vec2,
     separation: Vec2,
     alignment: Vec2,
+    // Towards the center of the whole flock, for boids that strayed too far from it.
+    center: Vec2,
+    // The neighbours counted, only recorded when they're drawn.
+    neighbour_entities: Option<Vec<Entity>>,
 }
 
 impl Calculations {
     fn reset(&mut self) {
         self.neighbours = 0;
+        self.cohesion_neighbours = 0;
+        self.weights = 0.0;
         self.cohesion = Vec2::ZERO;
         self.separation = Vec2::ZERO;
         self.alignment = Vec2::ZERO;
+        self.center = Vec2::ZERO;
+        self.neighbour_entities = None;
     }
 }
 
-const BIRD_SIZE: f32 = 1.0;
-
-fn setup(
-    params: Res<Parameters>,
-    mut commands: Commands,
-    meshes: ResMut<Assets<Mesh>>,
-    materials: ResMut<Assets<ColorMaterial>>,
-) {
-    commands.spawn(Camera2dBundle::default());
-    spawn_boids(params.number_of_boids, params, commands, meshes, materials);
+// Something the boids steer around.
+#[derive(Component, Reflect, Clone, Copy, Debug, PartialEq)]
+#[reflect(Component)]
+enum Obstacle {
+    Circle { radius: f32 },
+    // Aligned with the axes, to build walls and corridors out of.
+    Rectangle { half_extents: Vec2 },
 }
 
-fn spawn_boids(
-    how_many: usize,
-    params: Res<Parameters>,
-    mut commands: Commands,
-    mut meshes: ResMut<Assets<Mesh>>,
-    mut materials: ResMut<Assets<ColorMaterial>>,
-) {
-    for i in 1..=how_many {
-        let color = Color::hsl(360. * i as f32 / how_many as f32, 0.95, 0.7);
-        let weight = 1.0 + Exp::new(20.0).unwrap().sample(&mut thread_rng()) * 10.0;
-        let size = BIRD_SIZE * weight;
-
-        commands.spawn((
-            MaterialMesh2dBundle {
-                mesh: Mesh2dHandle(meshes.add(Triangle2d::new(
-                    Vec2::Y * size * 2.0,
-                    Vec2::new(-size, -size),
-                    Vec2::new(size, -size),
-                ))),
-                material: materials.add(color),
-                transform: Transform::from_xyz(
-                    thread_rng().gen_range(params.window_x_range()),
-                    thread_rng().gen_range(params.window_y_range()),
-                    0.,
-                ),
-                ..default()
-            },
-            Boid::new(
-                thread_rng().gen_range(-params.max_speed..params.max_speed),
-                thread_rng().gen_range(-params.max_speed..params.max_speed),
-                weight,
-            ),
-            Calculations::default(),
-        ));
+impl Default for Obstacle {
+    fn default() -> Self {
+        Obstacle::Circle { radius: 0.0 }
     }
 }
 
-fn flock(params: Res<Parameters>, mut query: Query<(&Transform, &mut Calculations, &mut Boid)>) {
-    let mut pairs = query.iter_combinations_mut();
-    while let Some([(t1, mut c1, b1), (t2, mut c2, b2)]) = pairs.fetch_next() {
-        if thread_rng().gen_range(0.0..=1.0) > params.fidelity {
-            continue;
+impl Obstacle {
+    // The point of the obstacle closest to the given position, both relative to its center.
+    fn closest_point(&self, offset: Vec2) -> Vec2 {
+        match *self {
+            Obstacle::Circle { radius } => offset.clamp_length_max(radius),
+            Obstacle::Rectangle { half_extents } => offset.clamp(-half_extents, half_extents),
         }
+    }
 
-        let distance = t1.translation.distance(t2.translation);
-        if distance > params.view_distance {
-            continue;
+    // Whether going from one position to another crosses the obstacle, both relative to its
+    // center.
+    fn intersects(&self, from: Vec2, to: Vec2) -> bool {
+        self.ray_hit(from, to - from).is_some_and(|t| t <= 1.0)
+    }
+
+    // How many times the direction a ray starting at the origin goes before it hits the obstacle,
+    // both relative to its center, or 0 if it starts inside.
+    fn ray_hit(&self, origin: Vec2, direction: Vec2) -> Option<f32> {
+        match *self {
+            // Where the ray first crosses the circle, solving |origin + direction * t| = radius.
+            Obstacle::Circle { radius } => {
+                let c = origin.length_squared() - radius * radius;
+                if c < 0.0 {
+                    return Some(0.0);
+                }
+                let a = direction.length_squared();
+                let b = origin.dot(direction);
+                let discriminant = b * b - a * c;
+                if a == 0.0 || discriminant < 0.0 {
+                    return None;
+                }
+                let t = (-b - discriminant.sqrt()) / a;
+                (t >= 0.0).then_some(t)
+            }
+            // Where the ray enters and leaves the slab between the sides along each axis.
+            Obstacle::Rectangle { half_extents } => {
+                let (mut enter, mut leave) = (0.0_f32, f32::INFINITY);
+                for axis in 0..2 {
+                    if direction[axis] == 0.0 {
+                        if origin[axis].abs() > half_extents[axis] {
+                            return None;
+                        }
+                        continue;
+                    }
+                    let a = (-half_extents[axis] - origin[axis]) / direction[axis];
+                    let b = (half_extents[axis] - origin[axis]) / direction[axis];
+                    enter = enter.max(a.min(b));
+                    leave = leave.min(a.max(b));
+                }
+                (enter <= leave).then_some(enter)
+            }
         }
-        let distance = distance.max(0.001); // Avoid division by zero.
-        let p1 = t1.translation.truncate();
-        let p2 = t2.translation.truncate();
+    }
+}
 
-        // Seperation should be stronger for boids closer to each other.
-        let separation_factor = 1.0 / distance.powf(params.separation_bias);
+// The shape of new obstacles.
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+enum ObstacleShape {
+    Circle,
+    Rectangle,
+}
 
-        // Cosine similarity between the two velocities: 1.0 if same, -1.0 if opposite.
-        let similarity =
-            b1.velocity.dot(b2.velocity) / (b1.velocity.length() * b2.velocity.length());
-        // When bias > 1, prefers boids already going in a similar drection.
-        // When bias < 1, prefers boids going in the opposite direction.
-        let bias = params.alignment_bias;
-        let alignment_factor = bias.powf(similarity) / if bias > 1.0 { bias } else { 1.0 / bias };
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum SpotlightShape {
+    Circle,
+    Rectangle,
+}
 
-        // Larger boids have a stronger influence.
-        let b1w = b1.weight.powi(2) / b2.weight.powi(2);
-        let b2w = b2.weight.powi(2) / b1.weight.powi(2);
+// A presentation aid that dims the boids outside of a region.
+#[derive(Resource)]
+struct Spotlight {
+    enabled: bool,
+    shape: SpotlightShape,
+    size: f32,
+    follow_cursor: bool,
+    dimming: f32,
+    center: Vec2,
+}
 
-        c1.neighbours += 1;
-        c1.cohesion += p2 * b2w;
-        c1.separation += (p1 - p2) * separation_factor * b2w;
-        c1.alignment += b2.velocity * alignment_factor * b2w;
+impl Default for Spotlight {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            shape: SpotlightShape::Circle,
+            size: 120.0,
+            follow_cursor: true,
+            dimming: 0.3,
+            center: Vec2::ZERO,
+        }
+    }
+}
 
-        c2.neighbours += 1;
-        c2.cohesion += p1 * b1w;
-        c2.separation += (p2 - p1) * separation_factor * b1w;
-        c2.alignment += b1.velocity * alignment_factor * b1w;
+impl Spotlight {
+    fn contains(&self, position: Vec2) -> bool {
+        let offset = position - self.center;
+        match self.shape {
+            SpotlightShape::Circle => offset.length() <= self.size,
+            SpotlightShape::Rectangle => offset.abs().cmple(Vec2::splat(self.size)).all(),
+        }
     }
 
-    for (_, mut c, mut b) in &mut query {
-        if c.neighbours <= 0 {
-            continue;
+    // How much to scale the lightness of a boid at the given position.
+    fn brightness(&self, position: Vec2) -> f32 {
+        if !self.enabled || self.contains(position) {
+            1.0
+        } else {
+            self.dimming
         }
+    }
+}
 
-        let cohesion = -(c.cohesion / c.neighbours as f32).clamp_length_max(params.steering_force);
-        let separation = c.separation.clamp_length_max(params.steering_force);
-        let alignment = c.alignment.clamp_length_max(params.steering_force);
+// The source of all randomness in the simulation, so runs with the same seed are reproducible.
+#[derive(Resource)]
+struct RngResource(StdRng);
 
-        b.velocity = b.velocity
-            + params.cohesion_force * cohesion
-            + params.separation_force * separation
-            + params.alignment_force * alignment;
-        b.velocity = b.velocity.clamp_length(params.min_speed, params.max_speed);
-        c.reset(); // Reset calculations for next frame.
+impl RngResource {
+    fn new(seed: u64) -> Self {
+        Self(StdRng::seed_from_u64(seed))
     }
 }
 
-fn adjust_number_of_boids(
-    mut commands: Commands,
-    meshes: ResMut<Assets<Mesh>>,
-    materials: ResMut<Assets<ColorMaterial>>,
-    params: Res<Parameters>,
-    query: Query<Entity, With<Boid>>,
+// What to draw to help understand how the boids behave.
+#[derive(Resource, Default)]
+struct DebugDraw {
+    show_velocity: bool,
+    // The cells of the spatial grid, and how many boids are in each of them.
+    show_grid: bool,
+    show_view_radius: bool,
+    show_neighbour_links: bool,
+    // The boid whose view radius and neighbours are drawn.
+    selected: Option<Entity>,
+    // The outline of the whole flock, and its area when last drawn.
+    show_hull: bool,
+    hull_shape: HullShape,
+    hull_area: f32,
+}
+
+// The order parameters of the flock, which tell an ordered flock flying the same way apart from
+// one milling around in circles, or one with no order at all.
+#[derive(Resource, Default)]
+struct Order {
+    polarization: f32,
+    milling: f32,
+}
+
+fn measure_order(mut order: ResMut<Order>, query: Query<(&Transform, &Boid)>) {
+    let boids = || {
+        query
+            .iter()
+            .map(|(t, b)| (t.translation.truncate(), b.velocity))
+    };
+    let count = query.iter().len().max(1) as f32;
+    let centroid = boids().map(|(position, _)| position).sum::<Vec2>() / count;
+    order.polarization = flocking::polarization(boids().map(|(_, velocity)| velocity));
+    order.milling = flocking::milling(boids(), centroid);
+}
+
+#[derive(Clone, Copy, Default, PartialEq)]
+enum HullShape {
+    #[default]
+    Convex,
+    BoundingBox,
+}
+
+fn draw_hull(
+    mut debug: ResMut<DebugDraw>,
+    query: Query<&Transform, With<Boid>>,
+    mut gizmos: Gizmos,
 ) {
-    let count = query.iter().count();
-    match count.cmp(&params.number_of_boids) {
-        Ordering::Less => spawn_boids(
-            params.number_of_boids - count,
-            params,
-            commands,
-            meshes,
-            materials,
-        ),
-        Ordering::Greater => {
-            for (i, e) in query.iter().enumerate() {
-                if i >= params.number_of_boids {
-                    commands.entity(e).despawn();
-                }
-            }
+    if !debug.show_hull {
+        return;
+    }
+    let points: Vec<Vec2> = query.iter().map(|t| t.translation.truncate()).collect();
+    let outline = match debug.hull_shape {
+        HullShape::Convex => flocking::convex_hull(points),
+        HullShape::BoundingBox if points.is_empty() => points,
+        HullShape::BoundingBox => {
+            let min = points.iter().copied().reduce(Vec2::min).unwrap_or_default();
+            let max = points.iter().copied().reduce(Vec2::max).unwrap_or_default();
+            vec![min, Vec2::new(max.x, min.y), max, Vec2::new(min.x, max.y)]
         }
-        _ => (),
     };
+    debug.hull_area = flocking::polygon_area(&outline);
+    gizmos.linestrip_2d(outline.iter().chain(outline.first()).copied(), Color::CYAN);
 }
 
-fn handle_walls(params: Res<Parameters>, mut query: Query<(&mut Transform, &mut Boid)>) {
-    for (mut t, mut b) in &mut query {
-        let x = t.translation.x;
-        if !params.window_x_range().contains(&x) && b.velocity.x.signum() == x.signum() {
-            if params.bounce_off_walls {
-                b.velocity.x *= -1.0;
-            } else {
-                t.translation.x *= -1.0;
-            }
+// How long of a path the velocity vectors show, in seconds.
+const VELOCITY_SCALE: f32 = 0.2;
+
+fn draw_debug(
+    window: Query<&Window, With<PrimaryWindow>>,
+    camera: Query<(&Camera, &GlobalTransform)>,
+    params: Res<Parameters>,
+    mut debug: ResMut<DebugDraw>,
+    query: Query<(Entity, &Transform, &Boid, &Calculations)>,
+    mut gizmos: Gizmos,
+) {
+    if debug.show_velocity {
+        for (_, t, boid, _) in &query {
+            let p = t.translation.truncate();
+            gizmos.line_2d(p, p + boid.velocity * VELOCITY_SCALE, Color::WHITE);
         }
-        let y = t.translation.y;
-        if !params.window_y_range().contains(&y) && b.velocity.y.signum() == y.signum() {
-            if params.bounce_off_walls {
-                b.velocity.y *= -1.0;
-            } else {
-                t.translation.y *= -1.0;
-            }
+    }
+
+    if !debug.show_view_radius && !debug.show_neighbour_links {
+        return;
+    }
+    // Focus on the boid closest to the mouse pointer.
+    let (camera, camera_transform) = camera.single();
+    if let Some(mouse_position) = cursor_position(window.single(), camera, camera_transform) {
+        debug.selected = query
+            .iter()
+            .min_by(|(_, a, _, _), (_, b, _, _)| {
+                let a = a.translation.truncate().distance_squared(mouse_position);
+                let b = b.translation.truncate().distance_squared(mouse_position);
+                a.total_cmp(&b)
+            })
+            .map(|(e, _, _, _)| e);
+    }
+    let Some((_, t, _, calculations)) = debug.selected.and_then(|e| query.get(e).ok()) else {
+        return;
+    };
+
+    let p = t.translation.truncate();
+    if debug.show_view_radius {
+        gizmos.circle_2d(p, params.view_distance, Color::WHITE);
+    }
+    if let Some(neighbours) = &calculations.neighbour_entities {
+        for (_, other, _, _) in neighbours.iter().filter_map(|&e| query.get(e).ok()) {
+            // Across the edge when wrapping around them.
+            let offset = params.offset(p, other.translation.truncate());
+            gizmos.line_2d(p, p + offset, Color::YELLOW);
         }
     }
 }
 
-fn handle_mouse(
-    window: Query<&Window, With<PrimaryWindow>>,
+// Draws the lines of the spatial grid over the window, outlining the cells with boids in them and
+// labelling them with how many.
+fn draw_grid(
+    mut contexts: EguiContexts,
     camera: Query<(&Camera, &GlobalTransform)>,
-    buttons: Res<ButtonInput<MouseButton>>,
     params: Res<Parameters>,
-    mut query: Query<(&Transform, &mut Boid)>,
+    debug: Res<DebugDraw>,
+    grid: Res<SpatialGrid>,
+    mut gizmos: Gizmos,
 ) {
-    // Follow or avoid the mouse pointer.
+    // The grid isn't built when using the k-d tree.
+    if !debug.show_grid || params.use_kdtree || grid.cell_size <= 0.0 {
+        return;
+    }
+
+    let half_world = params.world_size() / 2.0;
+    let first = grid.cell(-half_world);
+    let last = grid.cell(half_world);
+    let color = Color::rgba(1.0, 1.0, 1.0, 0.2);
+    for x in first.x..=last.x + 1 {
+        let x = x as f32 * grid.cell_size;
+        gizmos.line_2d(
+            Vec2::new(x, first.y as f32 * grid.cell_size),
+            Vec2::new(x, (last.y + 1) as f32 * grid.cell_size),
+            color,
+        );
+    }
+    for y in first.y..=last.y + 1 {
+        let y = y as f32 * grid.cell_size;
+        gizmos.line_2d(
+            Vec2::new(first.x as f32 * grid.cell_size, y),
+            Vec2::new((last.x + 1) as f32 * grid.cell_size, y),
+            color,
+        );
+    }
+
     let (camera, camera_transform) = camera.single();
-    if let Some(mouse_position) = window
-        .single()
-        .cursor_position()
-        .and_then(|cursor| camera.viewport_to_world(camera_transform, cursor))
-        .map(|ray| ray.origin)
-    {
-        // Left click attracts, right click repels.
-        let direction = match buttons.get_pressed().last() {
-            Some(MouseButton::Left) => 1.0,
-            Some(MouseButton::Right) => -1.0,
-            _ => return, // No effect when neither button is pressed.
-        };
+    let origin = camera
+        .logical_viewport_rect()
+        .map_or(Vec2::ZERO, |rect| rect.min);
+    let painter = contexts.ctx_mut().layer_painter(egui::LayerId::new(
+        egui::Order::Background,
+        egui::Id::new("grid"),
+    ));
+    for (cell, boids) in &grid.cells {
+        let center = (cell.as_vec2() + 0.5) * grid.cell_size;
+        gizmos.rect_2d(center, 0.0, Vec2::splat(grid.cell_size), Color::YELLOW);
+        if let Some(position) = camera.world_to_viewport(camera_transform, center.extend(0.0)) {
+            let position = position + origin;
+            painter.text(
+                egui::pos2(position.x, position.y),
+                egui::Align2::CENTER_CENTER,
+                boids.len(),
+                egui::FontId::monospace(10.0),
+                egui::Color32::YELLOW,
+            );
+        }
+    }
+}
 
-        let mouse_position = mouse_position.truncate();
-        for (t, mut boid) in &mut query {
-            let position = t.translation.truncate();
-            let distance = position.distance(mouse_position);
+// An overlay showing how many boids are in each cell of a grid covering the window.
+#[derive(Resource)]
+struct Heatmap {
+    enabled: bool,
+    cell_size: f32,
+    // The cell size and window size the cells were last laid out for.
+    layout: Option<(f32, Vec2)>,
+}
 
-            // Allow the mouse to affect boids further away.
-            if distance > params.view_distance * 4.0 {
-                continue;
+impl Default for Heatmap {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            cell_size: 40.0,
+            layout: None,
+        }
+    }
+}
+
+#[derive(Component)]
+struct HeatmapCell(IVec2);
+
+fn draw_heatmap(
+    mut commands: Commands,
+    params: Res<Parameters>,
+    grid: Res<SpatialGrid>,
+    mut heatmap: ResMut<Heatmap>,
+    boids: Query<&Transform, With<Boid>>,
+    mut cells: Query<(Entity, &HeatmapCell, &mut Sprite)>,
+) {
+    let layout = heatmap
+        .enabled
+        .then_some((heatmap.cell_size, params.world_size()));
+    if layout != heatmap.layout {
+        heatmap.layout = layout;
+        for (e, _, _) in &cells {
+            commands.entity(e).despawn();
+        }
+        if heatmap.enabled {
+            // Cover the window with cells lined up with the spatial grid.
+            let size = heatmap.cell_size;
+            let min = (params.min_position().truncate() / size).floor().as_ivec2();
+            let max = (params.max_position().truncate() / size).floor().as_ivec2();
+            for x in min.x..=max.x {
+                for y in min.y..=max.y {
+                    let cell = IVec2::new(x, y);
+                    let center = (cell.as_vec2() + 0.5) * size;
+                    commands.spawn((
+                        SpriteBundle {
+                            sprite: Sprite {
+                                color: Color::NONE,
+                                custom_size: Some(Vec2::splat(size)),
+                                ..default()
+                            },
+                            transform: Transform::from_translation(center.extend(-2.0)),
+                            ..default()
+                        },
+                        HeatmapCell(cell),
+                    ));
+                }
             }
-            let target = (mouse_position - position) * direction;
+        }
+        return; // The new cells show up next frame.
+    }
+    if !heatmap.enabled {
+        return;
+    }
 
-            boid.velocity = (boid.velocity
-                + target * params.steering_force * params.cohesion_force * 0.5)
-                .clamp_length_max(params.max_speed);
+    // The spatial grid already has the boids bucketed when its cells are the same size.
+    let counts: HashMap<IVec2, usize> = if !params.use_kdtree && grid.cell_size == heatmap.cell_size
+    {
+        grid.cells
+            .iter()
+            .map(|(&cell, boids)| (cell, boids.len()))
+            .collect()
+    } else {
+        let mut counts = HashMap::new();
+        for t in &boids {
+            let cell = (t.translation.truncate() / heatmap.cell_size)
+                .floor()
+                .as_ivec2();
+            *counts.entry(cell).or_default() += 1;
         }
+        counts
+    };
+    let densest = counts.values().copied().max().unwrap_or_default().max(1);
+    for (_, cell, mut sprite) in &mut cells {
+        // From blue for empty cells to red for the densest.
+        let t = counts.get(&cell.0).copied().unwrap_or_default() as f32 / densest as f32;
+        sprite.color = Color::hsla(240. * (1.0 - t), 0.9, 0.5, 0.3);
     }
 }
 
-fn fly(
-    time: Res<Time>,
-    mut materials: ResMut<Assets<ColorMaterial>>,
-    mut query: Query<(&mut Transform, &Handle<ColorMaterial>, &Boid)>,
-) {
-    // Slow-motion
-    // if time.elapsed().as_millis() % 500 > 15 {
-    //     return;
-    // }
+// Where an entity was after the last two simulation steps, so it can be drawn in between them
+// when the frame rate doesn't match the simulation rate.
+#[derive(Component)]
+struct Interpolated {
+    previous: Transform,
+    current: Transform,
+    // What was last drawn, to tell when something other than the simulation moved the entity.
+    rendered: Transform,
+}
 
-    for (mut transform, material_handle, boid) in &mut query {
-        let direction = (transform.rotation * Vec3::Y).truncate();
-        let target = boid.velocity.normalize();
-
-        // Rotate boid towards its velocity vector.
-        let target_rotation = Quat::from_rotation_arc_2d(direction, target);
-        transform.rotate(target_rotation);
-
-        // Color the boid based on its velocity angle.
-        if let Some(material) = materials.get_mut(material_handle) {
-            material.color = Color::hsl(
-                360. * (target.angle_between(Vec2::Y) + PI) / (2.0 * PI),
-                0.95,
-                0.7,
-            );
+impl Interpolated {
+    fn new(transform: Transform) -> Self {
+        Self {
+            previous: transform,
+            current: transform,
+            rendered: transform,
         }
+    }
+}
 
-        transform.translation.x += boid.velocity.x * time.delta_seconds();
-        transform.translation.y += boid.velocity.y * time.delta_seconds();
+// Puts entities back where the simulation left them before the next step, unless they've been
+// moved in the meantime, in which case they stay there.
+fn restore_transforms(mut query: Query<(&mut Transform, &mut Interpolated)>) {
+    for (mut t, mut i) in &mut query {
+        if *t == i.rendered {
+            *t = i.current;
+        } else {
+            i.previous = *t;
+            i.current = *t;
+        }
     }
 }
 
-fn parameters_ui(
-    mut contexts: EguiContexts,
-    mut params: ResMut<Parameters>,
-    mut boids: Query<&mut Transform, With<Boid>>,
+fn save_transforms(mut query: Query<(&Transform, &mut Interpolated)>) {
+    for (t, mut i) in &mut query {
+        i.previous = i.current;
+        i.current = *t;
+        i.rendered = *t;
+    }
+}
+
+// Draws entities in between their last two simulated transforms.
+fn interpolate_transforms(
+    time: Res<Time<Fixed>>,
+    params: Res<Parameters>,
+    mut query: Query<(&mut Transform, &mut Interpolated)>,
 ) {
-    egui::Window::new("Parameters")
-        .default_open(false)
-        .show(contexts.ctx_mut(), |ui| {
-            ui.add(
-                egui::Slider::new(&mut params.number_of_boids, 8..=2048)
-                    .logarithmic(true)
-                    .text("Number of boids"),
+    let s = time.overstep_fraction();
+    let half_world = params.world_size() / 2.0;
+    for (mut t, mut i) in &mut query {
+        let (previous, current) = (i.previous, i.current);
+        // Don't slide across the window when going past an edge.
+        *t = if (current.translation - previous.translation)
+            .truncate()
+            .abs()
+            .cmpgt(half_world)
+            .any()
+        {
+            current
+        } else {
+            Transform {
+                translation: previous.translation.lerp(current.translation, s),
+                rotation: previous.rotation.slerp(current.rotation, s),
+                scale: current.scale,
+            }
+        };
+        i.rendered = *t;
+    }
+}
+
+// Slowing down or speeding up time changes how many steps are taken per second, but not how long
+// each step is, so the boids behave the same at any speed.
+fn set_timestep(
+    params: Res<Parameters>,
+    mut fixed_time: ResMut<Time<Fixed>>,
+    mut virtual_time: ResMut<Time<Virtual>>,
+) {
+    if params.is_changed() {
+        fixed_time.set_timestep_hz(params.simulation_hz.into());
+        virtual_time.set_relative_speed(params.time_scale);
+    }
+}
+
+fn present_mode(params: &Parameters) -> PresentMode {
+    if params.vsync {
+        PresentMode::AutoVsync
+    } else {
+        PresentMode::AutoNoVsync
+    }
+}
+
+fn set_present_mode(params: Res<Parameters>, mut window: Query<&mut Window, With<PrimaryWindow>>) {
+    if !params.is_changed() {
+        return;
+    }
+    let mut window = window.single_mut();
+    // Only touch the window when it changes, setting it up again isn't free.
+    if window.present_mode != present_mode(&params) {
+        window.present_mode = present_mode(&params);
+    }
+}
+
+// How often to log the diagnostics, in seconds.
+const LOG_INTERVAL: f32 = 1.0;
+
+// Logs the smoothed value of every diagnostic when asked to, like LogDiagnosticsPlugin but with a
+// switch.
+fn log_diagnostics(
+    time: Res<Time<Real>>,
+    params: Res<Parameters>,
+    diagnostics: Res<DiagnosticsStore>,
+    mut since_logged: Local<f32>,
+) {
+    *since_logged += time.delta_seconds();
+    if !params.log_diagnostics || *since_logged < LOG_INTERVAL {
+        return;
+    }
+    *since_logged = 0.0;
+    for diagnostic in diagnostics
+        .iter()
+        .filter(|diagnostic| diagnostic.is_enabled)
+    {
+        if let Some(value) = diagnostic.smoothed() {
+            info!("{}: {:.4}{}", diagnostic.path(), value, diagnostic.suffix);
+        }
+    }
+}
+
+// Sleeps through what's left of the frame when frames go by faster than the cap, to spare the
+// CPU and the battery. Browsers already pace the frames themselves.
+#[cfg(not(target_arch = "wasm32"))]
+fn limit_frame_rate(params: Res<Parameters>, mut last_frame: Local<Option<Instant>>) {
+    if params.max_frame_rate > 0.0 {
+        let frame_time = std::time::Duration::from_secs_f32(1.0 / params.max_frame_rate);
+        if let Some(elapsed) = last_frame.map(|last_frame| last_frame.elapsed()) {
+            if elapsed < frame_time {
+                std::thread::sleep(frame_time - elapsed);
+            }
+        }
+    }
+    *last_frame = Some(Instant::now());
+}
+
+fn set_background(params: Res<Parameters>, mut clear_color: ResMut<ClearColor>) {
+    if params.is_changed() {
+        clear_color.0 = params.background;
+    }
+}
+
+// Whether the simulation is running, or paused and possibly advancing by a single frame.
+#[derive(Resource, Default)]
+struct SimState {
+    paused: bool,
+    step_once: bool,
+}
+
+fn is_simulating(state: Res<SimState>, scatter: Res<ScatterState>) -> bool {
+    // The boids are left alone while they're being scattered.
+    !scatter.is_active() && (!state.paused || state.step_once)
+}
+
+fn finish_step(mut state: ResMut<SimState>) {
+    if state.step_once {
+        state.step_once = false;
+    }
+}
+
+// Space pauses or resumes the simulation, the right arrow advances it by one frame.
+fn pause_controls(
+    mut contexts: EguiContexts,
+    keys: Res<ButtonInput<KeyCode>>,
+    mut state: ResMut<SimState>,
+) {
+    // Space and the arrows are for the text being typed into the parameters window then.
+    if contexts.ctx_mut().wants_keyboard_input() {
+        return;
+    }
+    if keys.just_pressed(KeyCode::Space) {
+        state.paused = !state.paused;
+    }
+    if keys.just_pressed(KeyCode::ArrowRight) {
+        state.paused = true;
+        state.step_once = true;
+    }
+}
+
+// How long the boids take to move to their new positions when scattered, in seconds.
+const SCATTER_DURATION: f32 = 0.5;
+
+// The boids moving to random positions, while the simulation waits for them to get there.
+#[derive(Resource, Default)]
+struct ScatterState {
+    elapsed: f32,
+    // Where each boid started from and where it's going.
+    moves: Vec<(Entity, Vec2, Vec2)>,
+}
+
+impl ScatterState {
+    fn is_active(&self) -> bool {
+        !self.moves.is_empty()
+    }
+}
+
+// Starts moving every boid to a random position.
+fn scatter_boids(
+    params: &Parameters,
+    rng: &mut StdRng,
+    scatter: &mut ScatterState,
+    boids: &Query<(Entity, &Transform), With<Boid>>,
+) {
+    scatter.elapsed = 0.0;
+    scatter.moves = boids
+        .iter()
+        .map(|(e, t)| {
+            let target = Vec2::new(
+                rng.gen_range(params.world_x_range()),
+                rng.gen_range(params.world_y_range()),
+            );
+            (e, t.translation.truncate(), target)
+        })
+        .collect();
+}
+
+fn animate_scatter(
+    time: Res<Time>,
+    mut scatter: ResMut<ScatterState>,
+    mut query: Query<(&mut Transform, &mut Interpolated), With<Boid>>,
+) {
+    if !scatter.is_active() {
+        return;
+    }
+    scatter.elapsed += time.delta_seconds();
+    let s = (scatter.elapsed / SCATTER_DURATION).min(1.0);
+    // Ease in and out.
+    let s = s * s * (3.0 - 2.0 * s);
+    for &(e, start, target) in &scatter.moves {
+        let Ok((mut t, mut i)) = query.get_mut(e) else {
+            continue; // It was removed.
+        };
+        t.translation = start.lerp(target, s).extend(t.translation.z);
+        // Nothing to interpolate until the simulation resumes from here.
+        *i = Interpolated::new(*t);
+    }
+    if scatter.elapsed >= SCATTER_DURATION {
+        scatter.moves.clear();
+    }
+}
+
+// The presets the number keys switch to, see Parameters::apply_builtin_preset().
+const BUILTIN_PRESETS: [&str; 4] = ["Default", "Tight flocks", "Swarm", "Schools"];
+
+// How many boids + and - add or remove.
+const BOIDS_STEP: usize = 32;
+
+// The most boids there can be, from the command line or the parameters window.
+const MAX_BOIDS: usize = 4096;
+
+// The keys and what they do, as listed in the help.
+const SHORTCUTS: [(&str, &str); 14] = [
+    ("Space", "Pause or resume"),
+    ("Right arrow", "Advance by one step"),
+    ("R", "Restart from random positions"),
+    ("B", "Switch what happens at the walls"),
+    ("G", "Show or hide the debug drawing"),
+    ("+ / -", "More or fewer boids"),
+    ("1 to 4", "Built-in presets"),
+    ("0", "Reset the camera"),
+    ("Mouse wheel", "Zoom"),
+    ("Middle drag", "Pan"),
+    ("Shift+click", "Place an obstacle"),
+    ("Ctrl+drag", "Move the center of gravity"),
+    ("E", "Startle the flock"),
+    ("Double click", "Startle the boids around the pointer"),
+];
+
+fn keyboard_shortcuts(
+    mut contexts: EguiContexts,
+    keys: Res<ButtonInput<KeyCode>>,
+    mut params: ResMut<Parameters>,
+    mut debug: ResMut<DebugDraw>,
+    mut rng: ResMut<RngResource>,
+    mut scatter: ResMut<ScatterState>,
+    boids: Query<(Entity, &Transform), With<Boid>>,
+) {
+    // Don't get in the way of typing into the parameters window.
+    if contexts.ctx_mut().wants_keyboard_input() {
+        return;
+    }
+
+    if keys.just_pressed(KeyCode::KeyR) {
+        scatter_boids(&params, &mut rng.0, &mut scatter, &boids);
+    }
+    if keys.just_pressed(KeyCode::KeyB) {
+        params.boundary_mode = match params.boundary_mode {
+            BoundaryMode::Bounce => BoundaryMode::Wrap,
+            BoundaryMode::Wrap => BoundaryMode::Mirror,
+            BoundaryMode::Mirror => BoundaryMode::SoftAvoid,
+            BoundaryMode::SoftAvoid => BoundaryMode::Sticky,
+            BoundaryMode::Sticky => BoundaryMode::Bounce,
+        };
+    }
+    if keys.just_pressed(KeyCode::KeyG) {
+        let show = !(debug.show_velocity || debug.show_view_radius || debug.show_neighbour_links);
+        debug.show_velocity = show;
+        debug.show_view_radius = show;
+        debug.show_neighbour_links = show;
+    }
+    if keys.any_just_pressed([KeyCode::Equal, KeyCode::NumpadAdd]) {
+        params.number_of_boids = (params.number_of_boids + BOIDS_STEP).min(MAX_BOIDS);
+    }
+    if keys.any_just_pressed([KeyCode::Minus, KeyCode::NumpadSubtract]) {
+        params.number_of_boids = params.number_of_boids.saturating_sub(BOIDS_STEP).max(8);
+    }
+    let presets = [
+        KeyCode::Digit1,
+        KeyCode::Digit2,
+        KeyCode::Digit3,
+        KeyCode::Digit4,
+    ];
+    for (preset, key) in presets.into_iter().enumerate() {
+        if keys.just_pressed(key) {
+            params.apply_builtin_preset(preset);
+        }
+    }
+}
+
+// Asks for the boids around a point to scatter away from it, like when startled.
+#[derive(Event)]
+struct Explode {
+    center: Vec2,
+}
+
+// How soon after the first click the second one makes a double click, in seconds.
+const DOUBLE_CLICK_TIME: f32 = 0.3;
+
+// E startles the flock from its center, double clicking startles the boids around the pointer.
+#[allow(clippy::too_many_arguments)]
+fn trigger_explosions(
+    mut contexts: EguiContexts,
+    window: Query<&Window, With<PrimaryWindow>>,
+    camera: Query<(&Camera, &GlobalTransform)>,
+    time: Res<Time<Real>>,
+    keys: Res<ButtonInput<KeyCode>>,
+    buttons: Res<ButtonInput<MouseButton>>,
+    tool: Res<ToolMode>,
+    centroid: Res<Centroid>,
+    mut explosions: EventWriter<Explode>,
+    mut last_click: Local<Option<f32>>,
+) {
+    let ctx = contexts.ctx_mut();
+    if keys.just_pressed(KeyCode::KeyE) && !ctx.wants_keyboard_input() {
+        explosions.send(Explode { center: centroid.0 });
+    }
+
+    // The other tools use clicks for something else.
+    if *tool != ToolMode::Flock
+        || !buttons.just_pressed(MouseButton::Left)
+        || ctx.is_pointer_over_area()
+    {
+        return;
+    }
+    let now = time.elapsed_seconds();
+    if last_click.is_some_and(|last| now - last < DOUBLE_CLICK_TIME) {
+        *last_click = None;
+        let (camera, camera_transform) = camera.single();
+        if let Some(position) = cursor_position(window.single(), camera, camera_transform) {
+            explosions.send(Explode { center: position });
+        }
+    } else {
+        *last_click = Some(now);
+    }
+}
+
+// Kicks the boids around each explosion away from it, harder closer to its center. The flock
+// reforms on its own afterwards.
+fn explode(
+    params: Res<Parameters>,
+    mut explosions: EventReader<Explode>,
+    mut query: Query<(&Transform, &mut Boid)>,
+) {
+    for explosion in explosions.read() {
+        for (t, mut boid) in &mut query {
+            let offset = params.offset(explosion.center, t.translation.truncate());
+            let distance = offset.length();
+            if distance > params.explode_radius {
+                continue;
+            }
+            // Right at the center, keep going the same way.
+            let direction = if offset == Vec2::ZERO {
+                boid.velocity.normalize_or_zero()
+            } else {
+                offset / distance
+            };
+            let strength = params.explode_strength * (1.0 - distance / params.explode_radius);
+            let (_, max_speed) = params.speed_limits(&boid);
+            boid.velocity = (boid.velocity + direction * strength).clamp_length_max(max_speed);
+        }
+    }
+}
+
+const BIRD_SIZE: f32 = 1.0;
+
+// What clicking in the window does.
+#[derive(Resource, Clone, Copy, Debug, Default, PartialEq)]
+enum ToolMode {
+    // Attract or repel the boids.
+    #[default]
+    Flock,
+    // Add or remove boids.
+    Spawn,
+    // Pick a boid to inspect.
+    Select,
+    // Gently draw the boids towards the pointer, without clicking.
+    Follow,
+    // Move obstacles around and resize them.
+    Obstacles,
+    // Place points that attract or repel the boids.
+    Attractors,
+}
+
+// How close to an obstacle's edge the mouse pointer has to be to grab it.
+const GRAB_RADIUS: f32 = 10.0;
+
+// How close to the mouse pointer a boid has to be to be removed.
+const SPAWN_TOOL_RADIUS: f32 = 20.0;
+
+// The meshes all boids share, one per shape, scaled to their size by their transform.
+#[derive(Resource)]
+struct BoidMesh {
+    triangle: Mesh2dHandle,
+    arrow: Mesh2dHandle,
+    circle: Mesh2dHandle,
+    // With --sprites, the boids are drawn as sprites of this texture instead of meshes, whatever
+    // their shape. Sprites sharing a texture are drawn in a single batch, while each mesh with its
+    // own material takes a draw call.
+    sprite: Option<Handle<Image>>,
+}
+
+impl BoidMesh {
+    fn get(&self, shape: BoidShape) -> Mesh2dHandle {
+        match shape {
+            BoidShape::Triangle => self.triangle.clone(),
+            BoidShape::Arrow => self.arrow.clone(),
+            BoidShape::Circle => self.circle.clone(),
+        }
+    }
+}
+
+impl FromWorld for BoidMesh {
+    fn from_world(world: &mut World) -> Self {
+        let mut meshes = world.resource_mut::<Assets<Mesh>>();
+        Self {
+            triangle: Mesh2dHandle(meshes.add(Triangle2d::new(
+                Vec2::Y * 2.0,
+                Vec2::new(-1.0, -1.0),
+                Vec2::new(1.0, -1.0),
+            ))),
+            arrow: Mesh2dHandle(meshes.add(Triangle2d::new(
+                Vec2::Y * 3.0,
+                Vec2::new(-0.6, -1.0),
+                Vec2::new(0.6, -1.0),
+            ))),
+            circle: Mesh2dHandle(meshes.add(Circle::new(1.0))),
+            sprite: None,
+        }
+    }
+}
+
+// The size of a boid sprite before scaling, the same as the triangle mesh. Its origin is a third
+// of the way up, where the mesh's is.
+const SPRITE_SIZE: Vec2 = Vec2::new(2.0, 3.0);
+const SPRITE_ANCHOR: Vec2 = Vec2::new(0.0, -1.0 / 6.0);
+
+// A white triangle like the triangle mesh, to be tinted with the color of each boid.
+#[cfg_attr(target_arch = "wasm32", allow(dead_code))]
+fn boid_sprite() -> Image {
+    let (width, height) = (32, 48);
+    let mut data = Vec::with_capacity(width * height * 4);
+    for row in 0..height {
+        for column in 0..width {
+            // Where the center of the pixel is on the mesh, whose tip is at (0, 2) and base at
+            // y = -1.
+            let x = -1.0 + 2.0 * (column as f32 + 0.5) / width as f32;
+            let y = 2.0 - 3.0 * (row as f32 + 0.5) / height as f32;
+            let inside = x.abs() <= (2.0 - y) / 3.0;
+            data.extend([255, 255, 255, if inside { 255 } else { 0 }]);
+        }
+    }
+    Image::new(
+        Extent3d {
+            width: width as u32,
+            height: height as u32,
+            depth_or_array_layers: 1,
+        },
+        TextureDimension::D2,
+        data,
+        TextureFormat::Rgba8UnormSrgb,
+        RenderAssetUsages::RENDER_WORLD,
+    )
+}
+
+// The color a boid is drawn with, whether it's a mesh or a sprite.
+fn boid_color(
+    materials: &Assets<ColorMaterial>,
+    material: Option<&Handle<ColorMaterial>>,
+    sprite: Option<&Sprite>,
+) -> Option<Color> {
+    match material {
+        Some(handle) => materials.get(handle).map(|material| material.color),
+        None => sprite.map(|sprite| sprite.color),
+    }
+}
+
+fn boid_color_mut<'a>(
+    materials: &'a mut Assets<ColorMaterial>,
+    material: Option<&Handle<ColorMaterial>>,
+    sprite: Option<Mut<'a, Sprite>>,
+) -> Option<&'a mut Color> {
+    match material {
+        Some(handle) => materials
+            .get_mut(handle)
+            .map(|material| &mut material.color),
+        None => sprite.map(|sprite| &mut sprite.into_inner().color),
+    }
+}
+
+// The order in which boids were spawned since the last respawn, counting from 0.
+#[derive(Component)]
+struct SpawnIndex(u64);
+
+// The index of the next boid to spawn.
+#[derive(Resource, Default)]
+struct SpawnCounter(u64);
+
+// The hue a boid was spawned with.
+#[derive(Component)]
+struct Hue(f32);
+
+// The color from the palette a boid was spawned with, if there's one.
+#[derive(Component)]
+struct PaletteColor(Color);
+
+// The recent positions of a boid, oldest first.
+#[derive(Component, Default)]
+struct Trail {
+    positions: VecDeque<Vec2>,
+}
+
+// Something that chases boids, and that boids flee from.
+#[derive(Component, Reflect)]
+#[reflect(Component)]
+struct Predator {
+    velocity: Vec2,
+    // Like Boid::energy. Predators saved before they could tire start rested.
+    #[reflect(default = "rested")]
+    energy: f32,
+}
+
+impl Default for Predator {
+    fn default() -> Self {
+        Self {
+            velocity: Vec2::ZERO,
+            energy: rested(),
+        }
+    }
+}
+
+fn rested() -> f32 {
+    1.0
+}
+
+// How much of their top speed boids and predators keep when exhausted.
+const TIRED_SPEED: f32 = 0.3;
+
+// The share of its top speed a boid or predator with the given energy can reach.
+fn stamina(energy: f32) -> f32 {
+    TIRED_SPEED + (1.0 - TIRED_SPEED) * energy
+}
+
+// Drains the energy of boids and predators going fast, and restores it as they slow down.
+fn tire(
+    time: Res<Time>,
+    params: Res<Parameters>,
+    mut boids: Query<&mut Boid>,
+    mut predators: Query<&mut Predator>,
+) {
+    let tire = |energy: f32, speed: f32, max_speed: f32| {
+        let effort = (speed / max_speed.max(MIN_DISTANCE)).min(1.0);
+        let change = params.energy_regen * (1.0 - effort) - params.energy_drain * effort;
+        (energy + change * time.delta_seconds()).clamp(0.0, 1.0)
+    };
+    for mut boid in &mut boids {
+        // The effort is relative to how fast it can go now, as steering limits it, see steer().
+        let (_, max_speed) = params.speed_limits(&boid);
+        boid.energy = tire(boid.energy, boid.velocity.length(), max_speed);
+    }
+    for mut predator in &mut predators {
+        predator.energy = tire(
+            predator.energy,
+            predator.velocity.length(),
+            params.predator_speed,
+        );
+    }
+}
+
+// Ages the boids, and replaces those past the maximum age by new ones anywhere, or coming in from
+// a random edge. The old boid is reused, so the number of boids stays the same.
+fn grow_old(
+    mut commands: Commands,
+    time: Res<Time>,
+    params: Res<Parameters>,
+    mut rng: ResMut<RngResource>,
+    mut query: Query<(
+        Entity,
+        &mut Transform,
+        &mut Boid,
+        &mut Trail,
+        &mut Interpolated,
+    )>,
+) {
+    let half_world = params.world_size() / 2.0;
+    for (e, mut t, mut boid, mut trail, mut interpolated) in &mut query {
+        boid.age += time.delta_seconds();
+        if params.max_age <= 0.0 || boid.age < params.max_age {
+            continue;
+        }
+
+        let rng = &mut rng.0;
+        let mut position = Vec2::new(
+            rng.gen_range(params.world_x_range()),
+            rng.gen_range(params.world_y_range()),
+        );
+        let mut direction = Vec2::from_angle(rng.gen_range(0.0..2.0 * PI));
+        if params.respawn_at_edges {
+            // Anywhere along one of the edges, heading into the window.
+            let (axis, side) = (rng.gen_range(0..2), if rng.gen() { 1.0 } else { -1.0 });
+            position[axis] = side * half_world[axis];
+            direction[axis] = -side * direction[axis].abs();
+        }
+        boid.age = 0.0;
+        boid.energy = 1.0;
+        let (min_speed, max_speed) = params.speed_limits(&boid);
+        boid.velocity = direction * rng.gen_range(min_speed..=max_speed);
+
+        t.translation = position.extend(t.translation.z);
+        t.rotation = Quat::from_rotation_arc_2d(Vec2::Y, direction);
+        trail.positions.clear();
+        // Appear there at once instead of sliding over from where it was.
+        interpolated.current = *t;
+        commands.entity(e).remove::<Perched>();
+    }
+}
+
+const PREDATOR_SIZE: f32 = 6.0;
+// How quickly predators turn towards their prey.
+const PREDATOR_AGILITY: f32 = 10.0;
+
+fn setup(
+    params: Res<Parameters>,
+    mut commands: Commands,
+    mesh: Res<BoidMesh>,
+    materials: ResMut<Assets<ColorMaterial>>,
+    rng: ResMut<RngResource>,
+    counter: ResMut<SpawnCounter>,
+) {
+    commands.spawn(Camera2dBundle::default());
+    let pattern = params.spawn_pattern;
+    spawn_boids(
+        params.number_of_boids,
+        pattern,
+        params,
+        commands,
+        Some(mesh),
+        Some(materials),
+        rng,
+        counter,
+    );
+}
+
+// Spawns boids following the pattern, only giving them a mesh when they're rendered.
+#[allow(clippy::too_many_arguments)]
+fn spawn_boids(
+    how_many: usize,
+    pattern: SpawnPattern,
+    params: Res<Parameters>,
+    mut commands: Commands,
+    mesh: Option<Res<BoidMesh>>,
+    materials: Option<ResMut<Assets<ColorMaterial>>>,
+    mut rng: ResMut<RngResource>,
+    mut counter: ResMut<SpawnCounter>,
+) {
+    let mut rendering = mesh.zip(materials);
+    let size = params.world_size();
+    let columns = ((how_many as f32 * size.x / size.y).sqrt().ceil() as usize).max(1);
+    let rows = how_many.div_ceil(columns).max(1);
+    // The heading the boids are aligned with, the same for all of them.
+    let common_heading =
+        (params.initial_alignment > 0.0).then(|| Vec2::from_angle(rng.0.gen_range(0.0..2.0 * PI)));
+    for i in 0..how_many {
+        let (position, heading) = match pattern {
+            SpawnPattern::Uniform => (None, None),
+            SpawnPattern::Cluster => {
+                let spread = Normal::new(0.0, size.min_element() / 20.0).unwrap();
+                let position = Vec2::new(spread.sample(&mut rng.0), spread.sample(&mut rng.0));
+                (Some(position), None)
+            }
+            SpawnPattern::Ring => {
+                let direction = Vec2::from_angle(2.0 * PI * i as f32 / how_many as f32);
+                (
+                    Some(direction * size.min_element() / 3.0),
+                    Some(direction.perp()),
+                )
+            }
+            SpawnPattern::Grid => {
+                let cell = size / Vec2::new(columns as f32, rows as f32);
+                let (x, y) = (i % columns, i / columns);
+                let position = -size / 2.0 + cell * (Vec2::new(x as f32, y as f32) + 0.5);
+                // Standing still, until their neighbours get them going.
+                (Some(position), Some(Vec2::ZERO))
+            }
+        };
+        spawn_boid(
+            &params,
+            &mut commands,
+            rendering
+                .as_mut()
+                .map(|(mesh, materials)| (&**mesh, &mut **materials)),
+            &mut rng.0,
+            &mut counter,
+            position,
+            heading,
+            common_heading,
+            360. * (i + 1) as f32 / how_many as f32,
+        );
+    }
+}
+
+// Spawns a boid at the given position, or at random if there's none. It heads in the given
+// direction at a medium speed, standing still for a zero one, or at a random velocity if there's
+// none, leaning towards the common heading as much as the initial alignment says. It's colored
+// with the given hue unless there are several species.
+#[allow(clippy::too_many_arguments)]
+fn spawn_boid(
+    params: &Parameters,
+    commands: &mut Commands,
+    rendering: Option<(&BoidMesh, &mut Assets<ColorMaterial>)>,
+    rng: &mut StdRng,
+    counter: &mut SpawnCounter,
+    position: Option<Vec2>,
+    heading: Option<Vec2>,
+    common_heading: Option<Vec2>,
+    hue: f32,
+) {
+    let species = rng.gen_range(0..params.number_of_species) as u8;
+    let hue = if params.number_of_species > 1 {
+        SPECIES_HUES[species as usize]
+    } else {
+        hue
+    };
+    // Boids take the colors of the palette in turn, unless the species tell them apart. A
+    // palette that doesn't parse is ignored, see parameters_ui().
+    let palette_color = parse_palette(&params.palette)
+        .ok()
+        .filter(|palette| !palette.is_empty() && params.number_of_species == 1)
+        .map(|palette| palette[(counter.0 % palette.len() as u64) as usize]);
+    let color = palette_color
+        .unwrap_or_else(|| Color::hsl(hue, params.boid_saturation, params.boid_lightness));
+    let weight = 1.0 + Exp::new(20.0).unwrap().sample(rng) * 10.0;
+    let size = BIRD_SIZE * weight;
+    let position = position.unwrap_or_else(|| {
+        Vec2::new(
+            rng.gen_range(params.world_x_range()),
+            rng.gen_range(params.world_y_range()),
+        )
+    });
+    let (min_speed, max_speed) = (
+        params.species(species).min_speed,
+        params.species(species).max_speed,
+    );
+    let velocity = match heading {
+        Some(heading) => heading * (min_speed + max_speed) / 2.0,
+        None => {
+            let velocity = Vec2::new(
+                rng.gen_range(-max_speed..max_speed),
+                rng.gen_range(-max_speed..max_speed),
+            );
+            match common_heading {
+                // Blend the random heading into the common one, at a random speed in between the
+                // limits so they don't all fly in lockstep.
+                Some(common) => {
+                    let random = velocity.try_normalize().unwrap_or(common);
+                    let heading = random
+                        .lerp(common, params.initial_alignment)
+                        .try_normalize()
+                        .unwrap_or(common);
+                    heading * rng.gen_range(min_speed..=max_speed)
+                }
+                None => velocity,
+            }
+        }
+    };
+    // Facing the way it's going, so it doesn't have to turn around first.
+    let facing = velocity.try_normalize().unwrap_or(Vec2::Y);
+    let transform = Transform::from_translation(position.extend(0.))
+        .with_rotation(Quat::from_rotation_arc_2d(Vec2::Y, facing))
+        .with_scale(Vec3::new(size, size, 1.0));
+    let boid = (
+        Boid::new(velocity.x, velocity.y, weight, species),
+        Calculations::default(),
+        Trail::default(),
+        Hue(hue),
+        SpawnIndex(counter.0),
+        Interpolated::new(transform),
+    );
+    counter.0 += 1;
+
+    let fade_in = Fade {
+        alpha: 0.0,
+        out: false,
+    };
+    let mut entity = match rendering {
+        Some((
+            BoidMesh {
+                sprite: Some(texture),
+                ..
+            },
+            _,
+        )) => commands.spawn((
+            SpriteBundle {
+                sprite: Sprite {
+                    color: color.with_a(0.0),
+                    custom_size: Some(SPRITE_SIZE),
+                    anchor: Anchor::Custom(SPRITE_ANCHOR),
+                    ..default()
+                },
+                texture: texture.clone(),
+                transform,
+                ..default()
+            },
+            boid,
+            fade_in,
+        )),
+        Some((mesh, materials)) => commands.spawn((
+            MaterialMesh2dBundle {
+                mesh: mesh.get(params.boid_shape),
+                material: materials.add(color.with_a(0.0)),
+                transform,
+                ..default()
+            },
+            boid,
+            fade_in,
+        )),
+        None => commands.spawn((TransformBundle::from_transform(transform), boid)),
+    };
+    if let Some(color) = palette_color {
+        entity.insert(PaletteColor(color));
+    }
+}
+
+// Asks for all the boids to be spawned again from the seed, at the start of the next step.
+#[derive(Resource, Default)]
+struct Respawn {
+    requested: bool,
+}
+
+// Asks to save or load the layout of obstacles and predators, see scenes::handle_requests().
+#[derive(Event)]
+#[cfg_attr(target_arch = "wasm32", allow(dead_code))]
+enum SceneRequest {
+    Save,
+    Load,
+}
+
+// Respawns the boids before anything else in the step, so nothing else uses the RNG in between
+// reseeding it and spawning the boids.
+#[allow(clippy::too_many_arguments)]
+fn respawn(
+    mut request: ResMut<Respawn>,
+    mut commands: Commands,
+    mesh: Res<BoidMesh>,
+    materials: ResMut<Assets<ColorMaterial>>,
+    params: Res<Parameters>,
+    mut rng: ResMut<RngResource>,
+    mut counter: ResMut<SpawnCounter>,
+    query: Query<Entity, With<Boid>>,
+) {
+    if !request.requested {
+        return;
+    }
+    request.requested = false;
+
+    *rng = RngResource::new(params.seed);
+    counter.0 = 0;
+    for e in &query {
+        commands.entity(e).despawn();
+    }
+    let pattern = params.spawn_pattern;
+    spawn_boids(
+        params.number_of_boids,
+        pattern,
+        params,
+        commands,
+        Some(mesh),
+        Some(materials),
+        rng,
+        counter,
+    );
+}
+
+// Accumulates the influence of the second boid on the first one, if it's within view.
+#[allow(clippy::too_many_arguments)]
+fn perceive(
+    params: &Parameters,
+    e1: Entity,
+    p1: Vec2,
+    b1: &Boid,
+    c1: &mut Calculations,
+    e2: Entity,
+    p2: Vec2,
+    b2: &Boid,
+    leader: bool,
+) {
+    let offset = params.offset(p2, p1);
+    // Most pairs are out of view, so reject them before taking a square root.
+    let distance_squared = offset.length_squared();
+    if distance_squared > params.view_distance * params.view_distance {
+        return;
+    }
+
+    // Boids at the same position would have no direction to separate in, so pick one.
+    let offset = if offset == Vec2::ZERO {
+        overlap_direction(e2, e1) * MIN_DISTANCE
+    } else {
+        offset
+    };
+
+    // Boids only see what's within their field of view.
+    if params.fov_degrees < 360.0
+        && b1.velocity.angle_between(-offset).abs() > params.fov_degrees.to_radians() / 2.0
+    {
+        return;
+    }
+
+    let distance = distance_squared.sqrt();
+    let separation_factor = flocking::separation_factor(
+        distance,
+        params.separation_distance,
+        params.separation_curve,
+        params.separation_bias,
+    );
+    let b2w = flocking::weight_ratio(b1.weight, b2.weight);
+
+    // Boids of all species keep apart, but only flock with their own kind.
+    if distance < params.separation_distance {
+        let closing_factor = if params.separation_velocity_bias > 0.0 {
+            // How fast they're getting closer, along the line between them.
+            let relative_velocity = b1.velocity - b2.velocity;
+            let closing_speed = -offset.dot(relative_velocity) / distance.max(MIN_DISTANCE);
+            flocking::closing_factor(
+                closing_speed,
+                b1.velocity.length() + b2.velocity.length(),
+                params.separation_velocity_bias,
+            )
+        } else {
+            1.0
+        };
+        c1.separation += offset * separation_factor * closing_factor * b2w;
+    }
+    if b1.species != b2.species {
+        return;
+    }
+
+    // Where the first boid sees the second relative to itself, which may be across an edge when
+    // wrapping around them, and where it expects it to be soon.
+    let p2_seen = -offset + b2.velocity * params.prediction_time;
+
+    c1.neighbours += 1;
+    if distance <= params.cohesion_distance {
+        // Boids are drawn to their leaders more than to each other.
+        let b2w = if leader {
+            b2w * params.leader_weight
+        } else {
+            b2w
+        };
+        c1.cohesion_neighbours += 1;
+        c1.weights += b2w;
+        c1.cohesion += p2_seen * b2w;
+    }
+    let min_speed_squared = params.alignment_min_speed * params.alignment_min_speed;
+    if distance <= params.alignment_distance
+        && b1.velocity.length_squared() >= min_speed_squared
+        && b2.velocity.length_squared() >= min_speed_squared
+    {
+        let alignment_factor =
+            flocking::alignment_factor(b1.velocity, b2.velocity, params.alignment_bias);
+        let alignment = match params.alignment_mode {
+            AlignmentMode::SummedVelocity => b2.velocity,
+            AlignmentMode::AverageHeading => b2.velocity.normalize_or_zero(),
+        };
+        c1.alignment += alignment * alignment_factor * b2w;
+    }
+    if let Some(neighbours) = &mut c1.neighbour_entities {
+        neighbours.push(e2);
+    }
+}
+
+// Accumulates the influence two boids have on one another.
+#[allow(clippy::too_many_arguments)]
+fn interact(
+    params: &Parameters,
+    e1: Entity,
+    p1: Vec2,
+    b1: &Boid,
+    c1: &mut Calculations,
+    e2: Entity,
+    p2: Vec2,
+    b2: &Boid,
+    c2: &mut Calculations,
+    leaders: [bool; 2],
+) {
+    // Leaders go their own way, see lead().
+    if !leaders[0] {
+        perceive(params, e1, p1, b1, c1, e2, p2, b2, leaders[1]);
+    }
+    if !leaders[1] {
+        perceive(params, e2, p2, b2, c2, e1, p1, b1, leaders[0]);
+    }
+}
+
+// The changes in velocity from the cohesion, separation, alignment and return to center forces.
+fn forces(params: &Parameters, c: &Calculations, b: &Boid) -> [Vec2; 4] {
+    let cohesion = if c.cohesion_neighbours > 0 {
+        let total = if params.weighted_cohesion {
+            c.weights
+        } else {
+            c.cohesion_neighbours as f32
+        };
+        c.cohesion / total
+    } else {
+        Vec2::ZERO // Only boids of other species around, or none close enough.
+    };
+
+    let species = params.species(b.species);
+    let steering = |direction, force| flocking::steering(direction, force, params.steering_force);
+    [
+        steering(cohesion, species.cohesion_force),
+        steering(c.separation, species.separation_force),
+        steering(c.alignment, species.alignment_force),
+        steering(c.center, params.cohesion_to_center),
+    ]
+}
+
+// Applies the accumulated calculations to the boid's velocity.
+fn steer(params: &Parameters, c: &Calculations, b: &mut Boid) {
+    if c.neighbours <= 0 && c.separation == Vec2::ZERO && c.center == Vec2::ZERO {
+        return;
+    }
+
+    let (min_speed, max_speed) = params.speed_limits(b);
+    b.velocity = flocking::combine(b.velocity, &forces(params, c, b), min_speed, max_speed);
+}
+
+// Buckets the boids into cells as large as the view distance, so each boid only needs to be
+// compared against the boids in its own cell and the eight cells around it.
+#[derive(Resource, Default)]
+struct SpatialGrid {
+    cell_size: f32,
+    cells: HashMap<IVec2, Vec<Entity>>,
+}
+
+impl SpatialGrid {
+    // The cell containing the given position.
+    fn cell(&self, position: Vec2) -> IVec2 {
+        (position / self.cell_size).floor().as_ivec2()
+    }
+
+    // The boids in the cell containing the given position and in the cells around it.
+    fn neighbours(&self, position: Vec2) -> impl Iterator<Item = Entity> + '_ {
+        let cell = self.cell(position);
+        (-1..=1)
+            .flat_map(move |x| (-1..=1).map(move |y| cell + IVec2::new(x, y)))
+            .filter_map(|cell| self.cells.get(&cell))
+            .flatten()
+            .copied()
+    }
+}
+
+fn build_grid(
+    params: Res<Parameters>,
+    mut grid: ResMut<SpatialGrid>,
+    query: Query<(Entity, &Transform), With<Boid>>,
+) {
+    if params.use_kdtree {
+        return;
+    }
+    grid.cell_size = params.view_distance.max(1.0);
+    grid.cells.clear();
+    for (e, t) in &query {
+        let cell = grid.cell(t.translation.truncate());
+        grid.cells.entry(cell).or_default().push(e);
+    }
+}
+
+// A 2D tree of the boids' positions. It's laid out implicitly: the median of each slice is the
+// node splitting it in two, alternating between the x and y axis at each level.
+#[derive(Resource, Default)]
+struct KdTree {
+    points: Vec<(Entity, Vec2)>,
+}
+
+impl KdTree {
+    fn build(&mut self, points: impl Iterator<Item = (Entity, Vec2)>) {
+        self.points.clear();
+        self.points.extend(points);
+        Self::split(&mut self.points, 0);
+    }
+
+    fn split(points: &mut [(Entity, Vec2)], axis: usize) {
+        if points.len() <= 1 {
+            return;
+        }
+        let mid = points.len() / 2;
+        points.select_nth_unstable_by(mid, |a, b| a.1[axis].total_cmp(&b.1[axis]));
+        let (left, right) = points.split_at_mut(mid);
+        Self::split(left, 1 - axis);
+        Self::split(&mut right[1..], 1 - axis);
+    }
+
+    // Adds the boids within the radius of the given position to `found`.
+    fn within(&self, position: Vec2, radius: f32, found: &mut Vec<Entity>) {
+        Self::search(&self.points, 0, position, radius, found);
+    }
+
+    fn search(
+        points: &[(Entity, Vec2)],
+        axis: usize,
+        position: Vec2,
+        radius: f32,
+        found: &mut Vec<Entity>,
+    ) {
+        if points.is_empty() {
+            return;
+        }
+        let mid = points.len() / 2;
+        let (entity, point) = points[mid];
+        if point.distance(position) <= radius {
+            found.push(entity);
+        }
+
+        // Only descend into the halves the radius overlaps.
+        let delta = position[axis] - point[axis];
+        if delta <= radius {
+            Self::search(&points[..mid], 1 - axis, position, radius, found);
+        }
+        if delta >= -radius {
+            Self::search(&points[mid + 1..], 1 - axis, position, radius, found);
+        }
+    }
+}
+
+fn build_kdtree(
+    params: Res<Parameters>,
+    mut tree: ResMut<KdTree>,
+    query: Query<(Entity, &Transform), With<Boid>>,
+) {
+    if !params.use_kdtree {
+        return;
+    }
+    tree.build(query.iter().map(|(e, t)| (e, t.translation.truncate())));
+}
+
+// Adds the boids that might be within view of the given position to `candidates`.
+fn find_candidates(
+    params: &Parameters,
+    grid: &SpatialGrid,
+    tree: &KdTree,
+    position: Vec2,
+    candidates: &mut Vec<Entity>,
+) {
+    for position in params.neighbourhood(position, params.view_distance) {
+        if params.use_kdtree {
+            tree.within(position, params.view_distance, candidates);
+        } else {
+            candidates.extend(grid.neighbours(position));
+        }
+    }
+    candidates.sort_unstable();
+    candidates.dedup();
+}
+
+// Accumulates the influence of its neighbours on a single boid, only counting the closest
+// max_neighbours of them when there's a limit. The position of each neighbour, the boid itself
+// and whether it's a leader are looked up with `boid`.
+#[allow(clippy::too_many_arguments)]
+fn perceive_neighbours<'a>(
+    params: &Parameters,
+    grid: &SpatialGrid,
+    tree: &KdTree,
+    boid: impl Fn(Entity) -> Option<(Vec2, &'a Boid, bool)>,
+    seed: u64,
+    e1: Entity,
+    p1: Vec2,
+    b1: &Boid,
+    c1: &mut Calculations,
+) {
+    let mut candidates = Vec::new();
+    find_candidates(params, grid, tree, p1, &mut candidates);
+    let mut neighbours: Vec<(Entity, Vec2, &Boid, bool)> = candidates
+        .into_iter()
+        .filter(|&e2| e2 != e1)
+        .filter_map(|e2| boid(e2).map(|(p2, b2, leader)| (e2, p2, b2, leader)))
+        .collect();
+    if params.max_neighbours > 0 {
+        neighbours.sort_by(|(_, a, _, _), (_, b, _, _)| {
+            let a = params.offset(p1, *a).length_squared();
+            let b = params.offset(p1, *b).length_squared();
+            a.total_cmp(&b)
+        });
+    }
+    for (e2, p2, b2, leader) in neighbours {
+        if params.max_neighbours > 0 && c1.neighbours >= params.max_neighbours as i32 {
+            break;
+        }
+        if !flocking::sees(seed, e1, e2, params.fidelity) {
+            continue;
+        }
+        perceive(params, e1, p1, b1, c1, e2, p2, b2, leader);
+    }
+}
+
+// The total of each of the flocking forces over the whole flock, measured at every step.
+const FORCE_DIAGNOSTICS: [(DiagnosticPath, &str); 3] = [
+    (DiagnosticPath::const_new("flock/cohesion"), "Cohesion"),
+    (DiagnosticPath::const_new("flock/separation"), "Separation"),
+    (DiagnosticPath::const_new("flock/alignment"), "Alignment"),
+];
+
+// How many steps of the forces to keep.
+const FORCE_HISTORY: usize = 300;
+
+// How many steps the graph of the forces averages over.
+const FORCE_SMOOTHING: usize = 10;
+
+fn register_force_diagnostics(app: &mut App) {
+    for (path, _) in FORCE_DIAGNOSTICS {
+        app.register_diagnostic(Diagnostic::new(path).with_max_history_length(FORCE_HISTORY));
+    }
+}
+
+// Records the totals of the flocking forces over the whole flock for this step.
+fn measure_forces(diagnostics: &mut Diagnostics, totals: [f64; 3]) {
+    for ((path, _), total) in FORCE_DIAGNOSTICS.iter().zip(totals) {
+        diagnostics.add_measurement(path, || total);
+    }
+}
+
+#[allow(clippy::too_many_arguments)]
+fn flock(
+    params: Res<Parameters>,
+    debug: Res<DebugDraw>,
+    centroid: Res<Centroid>,
+    mut rng: ResMut<RngResource>,
+    grid: Res<SpatialGrid>,
+    tree: Res<KdTree>,
+    mut query: Query<(Entity, &Transform, &Boid, &mut Calculations, Has<Leader>)>,
+    boids: Query<(&Transform, &Boid, Has<Leader>)>,
+    mut diagnostics: Diagnostics,
+) {
+    if params.background_flocking {
+        return;
+    }
+    let start = Instant::now();
+
+    // The calculations are kept until the next frame, so they can be shown.
+    for (e, t, _, mut c, _) in &mut query {
+        c.reset();
+        c.center = pull_to_center(&params, centroid.0, t.translation.truncate());
+        if debug.show_neighbour_links && debug.selected == Some(e) {
+            c.neighbour_entities = Some(Vec::new());
+        }
+    }
+
+    // Picks which pairs of boids see each other this step, see flocking::sees().
+    let seed: u64 = rng.0.gen();
+    let boid = |e| {
+        boids
+            .get(e)
+            .ok()
+            .map(|(t, b, leader)| (t.translation.truncate(), b, leader))
+    };
+    if params.parallel_flocking {
+        // Each boid accumulates its own calculations, looking up its neighbours in the grid or
+        // tree. This is only safe because nothing modifies them while flocking: they're shared
+        // read-only between threads, and only rebuilt before flocking starts.
+        query
+            .par_iter_mut()
+            .for_each(|(e1, t1, b1, mut c1, leader)| {
+                if !leader {
+                    perceive_neighbours(
+                        &params,
+                        &grid,
+                        &tree,
+                        boid,
+                        seed,
+                        e1,
+                        t1.translation.truncate(),
+                        b1,
+                        &mut c1,
+                    );
+                }
+            });
+    } else if params.max_neighbours > 0 {
+        // Limiting the neighbours of one boid doesn't limit those of the other, so each boid
+        // has to look at its neighbours on its own.
+        for (e1, t1, b1, mut c1, leader) in &mut query {
+            if !leader {
+                perceive_neighbours(
+                    &params,
+                    &grid,
+                    &tree,
+                    boid,
+                    seed,
+                    e1,
+                    t1.translation.truncate(),
+                    b1,
+                    &mut c1,
+                );
+            }
+        }
+    } else {
+        let positions: Vec<(Entity, Vec2)> = query
+            .iter()
+            .map(|(e, t, _, _, _)| (e, t.translation.truncate()))
+            .collect();
+        let mut candidates = Vec::new();
+        for &(e1, p1) in &positions {
+            candidates.clear();
+            find_candidates(&params, &grid, &tree, p1, &mut candidates);
+            for &e2 in &candidates {
+                if e2 <= e1 {
+                    continue; // Only consider each pair once.
+                }
+                if !flocking::sees(seed, e1, e2, params.fidelity) {
+                    continue;
+                }
+                let Ok([(_, t1, b1, mut c1, l1), (_, t2, b2, mut c2, l2)]) =
+                    query.get_many_mut([e1, e2])
+                else {
+                    continue;
+                };
+                interact(
+                    &params,
+                    e1,
+                    t1.translation.truncate(),
+                    b1,
+                    &mut c1,
+                    e2,
+                    t2.translation.truncate(),
+                    b2,
+                    &mut c2,
+                    [l1, l2],
+                );
+            }
+        }
+    }
+
+    let mut totals = [0.0; 3];
+    for (_, _, b, c, _) in &query {
+        for (total, force) in totals.iter_mut().zip(forces(&params, c, b)) {
+            *total += force.length() as f64;
+        }
+    }
+    measure_forces(&mut diagnostics, totals);
+
+    debug!(
+        "Flocked {} boids in {:?}",
+        query.iter().len(),
+        start.elapsed()
+    );
+}
+
+// How hard a boid at the given position is pulled back towards the center of the flock. The
+// further it strays beyond the radius, the harder it's pulled.
+fn pull_to_center(params: &Parameters, centroid: Vec2, position: Vec2) -> Vec2 {
+    let offset = centroid - position;
+    let beyond = (offset.length() - params.center_radius).max(0.0);
+    offset.normalize_or_zero() * beyond / params.center_radius.max(1.0)
+}
+
+// How fast the heading lonely boids wander towards changes at most, in radians per second.
+const WANDER_RATE: f32 = PI / 2.0;
+
+// Lonely boids wander around until they find others. They turn towards a heading of their own,
+// which drifts at random by up to WANDER_RATE, and at up to wander_strength turns per second.
+// Both are per second rather than per step, so boids wander the same whatever the simulation rate.
+fn wander(params: &Parameters, c: &Calculations, b: &mut Boid, rng: &mut StdRng, dt: f32) {
+    if c.neighbours != 0 || params.wander_strength <= 0.0 || b.velocity == Vec2::ZERO {
+        return;
+    }
+    b.wander_angle += rng.gen_range(-1.0..=1.0) * WANDER_RATE * dt;
+    let max_turn = params.wander_strength * 2.0 * PI * dt;
+    let turn = b.velocity.angle_between(Vec2::from_angle(b.wander_angle));
+    b.velocity = Vec2::from_angle(turn.clamp(-max_turn, max_turn)).rotate(b.velocity);
+}
+
+fn steer_boids(
+    time: Res<Time>,
+    params: Res<Parameters>,
+    mut rng: ResMut<RngResource>,
+    mut query: Query<(&Calculations, &mut Boid), Without<Leader>>,
+) {
+    if params.background_flocking {
+        return;
+    }
+    for (c, mut b) in &mut query {
+        steer(&params, c, &mut b);
+        wander(&params, c, &mut b, &mut rng.0, time.delta_seconds());
+    }
+}
+
+// Runs the flocking on a background task, so a slow tick doesn't stall rendering.
+//
+// The state of the boids is kept in two buffers: the front one holds the last completed tick and
+// the previous one the tick before it. Each tick runs on a task which flocks and moves the boids of
+// the front buffer, the way flock(), steer_boids() and fly() do, into a back buffer of its own.
+// Once it completes, the back buffer becomes the front one and the front one the previous one, in
+// one go at the start of a simulation step. In the meantime the boids are drawn in between the
+// previous and front buffers, see show_background_flock(), so they move smoothly however long a
+// tick takes.
+//
+// This adds latency: what's drawn is up to a tick behind the front buffer, which is itself a tick
+// behind the one being computed. Anything else steering the boids, like the mouse or obstacles,
+// only changes their velocities, which are taken from the boids when a tick starts, so it shows up
+// to two ticks later.
+#[derive(Resource, Default)]
+struct BackgroundFlock {
+    previous: FlockBuffer,
+    front: FlockBuffer,
+    // How long it's been since the buffers were swapped, in seconds.
+    since_swap: f32,
+    // The back buffer being computed, with the totals of the flocking forces for the tick.
+    task: Option<Task<(FlockBuffer, [f64; 3])>>,
+}
+
+type FlockBuffer = HashMap<Entity, BoidState>;
+
+// Where a boid is and where it's going, as of a tick of the background flocking.
+#[derive(Clone, Copy)]
+struct BoidState {
+    position: Vec2,
+    velocity: Vec2,
+    wander_angle: f32,
+}
+
+#[allow(clippy::too_many_arguments)]
+fn flock_in_background(
+    time: Res<Time>,
+    params: Res<Parameters>,
+    centroid: Res<Centroid>,
+    mut rng: ResMut<RngResource>,
+    mut background: ResMut<BackgroundFlock>,
+    mut query: Query<(Entity, &Transform, &mut Boid, Has<Leader>)>,
+    mut diagnostics: Diagnostics,
+) {
+    if !params.background_flocking {
+        // Dropping the task cancels it.
+        *background = BackgroundFlock::default();
+        return;
+    }
+
+    // Swap in the back buffer of the last tick once it's ready.
+    if let Some(task) = background.task.as_mut() {
+        let Some((back, totals)) = block_on(future::poll_once(task)) else {
+            return; // Still running, keep drawing from the current buffers.
+        };
+        let background = &mut *background;
+        background.task = None;
+        background.previous = std::mem::replace(&mut background.front, back);
+        background.since_swap = 0.0;
+        measure_forces(&mut diagnostics, totals);
+        // Leaders don't flock, see lead().
+        for (e, _, mut boid, leader) in &mut query {
+            if let (Some(state), false) = (background.front.get(&e), leader) {
+                boid.velocity = state.velocity;
+                boid.wander_angle = state.wander_angle;
+            }
+        }
+    }
+
+    // The grid and tree are only up to date with the boids at this step, so the task builds a
+    // tree of its own snapshot to find the neighbours in.
+    let params = Parameters {
+        use_kdtree: true,
+        ..params.clone()
+    };
+    let centroid = centroid.0;
+    let dt = time.delta_seconds();
+    let seed: u64 = rng.0.gen();
+    // Boids spawned since the last tick start from where they are.
+    let boids: Vec<(Entity, Vec2, Boid, bool)> = query
+        .iter()
+        .map(|(e, t, b, leader)| {
+            let position = background
+                .front
+                .get(&e)
+                .map_or(t.translation.truncate(), |state| state.position);
+            (e, position, b.clone(), leader)
+        })
+        .collect();
+    background.task = Some(AsyncComputeTaskPool::get().spawn(async move {
+        let mut tree = KdTree::default();
+        tree.build(boids.iter().map(|&(e, p, _, _)| (e, p)));
+        let index: HashMap<Entity, usize> = boids
+            .iter()
+            .enumerate()
+            .map(|(i, &(e, ..))| (e, i))
+            .collect();
+        let boid = |e| {
+            index
+                .get(&e)
+                .map(|&i| (boids[i].1, &boids[i].2, boids[i].3))
+        };
+        let grid = SpatialGrid::default();
+        let mut rng = StdRng::seed_from_u64(seed);
+
+        let mut totals = [0.0; 3];
+        let back = boids
+            .iter()
+            .map(|&(e, p, ref b, leader)| {
+                let mut steered = b.clone();
+                if !leader {
+                    let mut c = Calculations {
+                        center: pull_to_center(&params, centroid, p),
+                        ..default()
+                    };
+                    perceive_neighbours(&params, &grid, &tree, boid, seed, e, p, b, &mut c);
+                    for (total, force) in totals.iter_mut().zip(forces(&params, &c, b)) {
+                        *total += force.length() as f64;
+                    }
+                    steer(&params, &c, &mut steered);
+                    wander(&params, &c, &mut steered, &mut rng, dt);
+                }
+                let mut velocity = steered.velocity;
+                let mut position = (p + velocity * params.speed_multiplier * dt).extend(0.0);
+                keep_in_bounds(&params, &mut position, &mut velocity);
+                let state = BoidState {
+                    position: position.truncate(),
+                    velocity,
+                    wander_angle: steered.wander_angle,
+                };
+                (e, state)
+            })
+            .collect();
+        (back, totals)
+    }));
+}
+
+// Draws the boids in between the previous and front buffers of the background flocking, as far
+// along as the time since they were swapped, see BackgroundFlock.
+fn show_background_flock(
+    time: Res<Time>,
+    fixed: Res<Time<Fixed>>,
+    params: Res<Parameters>,
+    mut background: ResMut<BackgroundFlock>,
+    mut query: Query<(Entity, &mut Transform), With<Boid>>,
+) {
+    if !params.background_flocking {
+        return;
+    }
+    background.since_swap += time.delta_seconds();
+    let s = (background.since_swap / fixed.timestep().as_secs_f32()).min(1.0);
+    let half_world = params.world_size() / 2.0;
+    for (e, mut t) in &mut query {
+        let Some(front) = background.front.get(&e) else {
+            continue;
+        };
+        let previous = background.previous.get(&e).unwrap_or(front);
+        // Don't slide across the window when going past an edge.
+        let position = if (front.position - previous.position)
+            .abs()
+            .cmpgt(half_world)
+            .any()
+        {
+            front.position
+        } else {
+            previous.position.lerp(front.position, s)
+        };
+        t.translation = position.extend(t.translation.z);
+        if let Some(heading) = previous.velocity.lerp(front.velocity, s).try_normalize() {
+            t.rotation = Quat::from_rotation_arc_2d(Vec2::Y, heading);
+        }
+    }
+}
+
+// How many boids are spawned or despawned per frame at most when changing their number, so large
+// changes are spread over several frames.
+const SPAWN_RATE: usize = 32;
+
+// How long boids take to fade in when spawned and out when despawned, in seconds.
+const FADE_TIME: f32 = 0.3;
+
+// A boid fading in, or fading out to be despawned.
+#[derive(Component)]
+struct Fade {
+    alpha: f32,
+    out: bool,
+}
+
+fn adjust_number_of_boids(
+    mut commands: Commands,
+    mesh: Option<Res<BoidMesh>>,
+    materials: Option<ResMut<Assets<ColorMaterial>>>,
+    params: Res<Parameters>,
+    rng: ResMut<RngResource>,
+    counter: ResMut<SpawnCounter>,
+    query: Query<(Entity, &SpawnIndex, Option<&Fade>), With<Boid>>,
+) {
+    // Boids fading out are as good as gone.
+    let remaining = || {
+        query
+            .iter()
+            .filter(|(_, _, fade)| !fade.is_some_and(|fade| fade.out))
+    };
+    let count = remaining().count();
+    match count.cmp(&params.number_of_boids) {
+        // A whole new flock is spawned at once, to lay it out following the pattern.
+        Ordering::Less if count == 0 => spawn_boids(
+            params.number_of_boids,
+            params.spawn_pattern,
+            params,
+            commands,
+            mesh,
+            materials,
+            rng,
+            counter,
+        ),
+        Ordering::Less => spawn_boids(
+            (params.number_of_boids - count).min(SPAWN_RATE),
+            // Boids added to the flock are spread out.
+            SpawnPattern::Uniform,
+            params,
+            commands,
+            mesh,
+            materials,
+            rng,
+            counter,
+        ),
+        Ordering::Greater => {
+            // The most recently spawned boids go first, so the same ones remain every time.
+            let mut boids: Vec<(u64, Entity)> = remaining().map(|(e, i, _)| (i.0, e)).collect();
+            boids.sort_unstable_by(|a, b| b.cmp(a));
+            for &(_, e) in boids
+                .iter()
+                .take((count - params.number_of_boids).min(SPAWN_RATE))
+            {
+                if mesh.is_some() {
+                    commands.entity(e).insert(Fade {
+                        alpha: 1.0,
+                        out: true,
+                    });
+                } else {
+                    commands.entity(e).despawn();
+                }
+            }
+        }
+        _ => (),
+    };
+}
+
+// Keeps something moving at the given velocity inside the window.
+fn keep_in_bounds(params: &Parameters, translation: &mut Vec3, velocity: &mut Vec2) {
+    let half_world = params.world_size() / 2.0;
+    // Whether it's past an edge along an axis, and still heading further out.
+    let outside = |p: f32, v: f32, half: f32| p.abs() > half && v.signum() == p.signum();
+
+    match params.boundary_mode {
+        BoundaryMode::Wrap => {
+            // Reappear on the opposite edge, carrying over how far past the edge it went.
+            let size = params.world_size();
+            let p = (translation.truncate() + size / 2.0).rem_euclid(size) - size / 2.0;
+            translation.x = p.x;
+            translation.y = p.y;
+        }
+        // Soft avoidance should turn boids around before they get there, but the walls still
+        // bounce any that come in too fast to turn in time. Boids stick to sticky walls before
+        // getting here, see handle_walls(), so only predators bounce off them.
+        BoundaryMode::Bounce | BoundaryMode::SoftAvoid | BoundaryMode::Sticky => {
+            if outside(translation.x, velocity.x, half_world.x) {
+                velocity.x *= -1.0;
+            }
+            if outside(translation.y, velocity.y, half_world.y) {
+                velocity.y *= -1.0;
+            }
+        }
+        BoundaryMode::Mirror => {
+            if outside(translation.x, velocity.x, half_world.x) {
+                translation.x *= -1.0;
+            }
+            if outside(translation.y, velocity.y, half_world.y) {
+                translation.y *= -1.0;
+            }
+        }
+    }
+}
+
+// Reassigns the boids of species that no longer exist.
+fn adjust_number_of_species(
+    params: Res<Parameters>,
+    mut rng: ResMut<RngResource>,
+    mut query: Query<&mut Boid>,
+) {
+    if !params.is_changed() {
+        return;
+    }
+    for mut boid in &mut query {
+        if boid.species as usize >= params.number_of_species {
+            boid.species = rng.0.gen_range(0..params.number_of_species) as u8;
+        }
+    }
+}
+
+fn adjust_number_of_predators(
+    mut commands: Commands,
+    mut meshes: ResMut<Assets<Mesh>>,
+    mut materials: ResMut<Assets<ColorMaterial>>,
+    params: Res<Parameters>,
+    mut rng: ResMut<RngResource>,
+    query: Query<Entity, With<Predator>>,
+) {
+    let count = query.iter().count();
+    for e in query.iter().skip(params.number_of_predators) {
+        commands.entity(e).despawn();
+    }
+    for _ in count..params.number_of_predators {
+        let transform = Transform::from_xyz(
+            rng.0.gen_range(params.world_x_range()),
+            rng.0.gen_range(params.world_y_range()),
+            1.,
+        );
+        commands.spawn((
+            predator_bundle(&mut meshes, &mut materials, transform),
+            Interpolated::new(transform),
+            Predator {
+                velocity: Vec2::new(
+                    rng.0
+                        .gen_range(-params.predator_speed..params.predator_speed),
+                    rng.0
+                        .gen_range(-params.predator_speed..params.predator_speed),
+                ),
+                ..default()
+            },
+        ));
+    }
+}
+
+// A boid that leads the others, following its own path instead of flocking.
+#[derive(Component)]
+struct Leader {
+    // Where in its wave the leader is, so leaders don't all weave in step.
+    phase: f32,
+}
+
+// How far leaders swing to either side when weaving, in radians, and how often, in radians per
+// second.
+const LEADER_SWING: f32 = 0.8;
+const LEADER_FREQUENCY: f32 = 1.5;
+
+// How large the ring around each leader is.
+const LEADER_RING: f32 = 12.0;
+
+#[allow(clippy::type_complexity)]
+fn adjust_number_of_leaders(
+    mut commands: Commands,
+    params: Res<Parameters>,
+    mut rng: ResMut<RngResource>,
+    followers: Query<(Entity, &SpawnIndex), (With<Boid>, Without<Leader>)>,
+    leaders: Query<(Entity, &SpawnIndex), With<Leader>>,
+) {
+    let count = leaders.iter().len();
+    if count > params.number_of_leaders {
+        let mut leaders: Vec<(u64, Entity)> = leaders.iter().map(|(e, i)| (i.0, e)).collect();
+        leaders.sort_unstable_by(|a, b| b.cmp(a));
+        for &(_, e) in &leaders[..count - params.number_of_leaders] {
+            commands.entity(e).remove::<Leader>();
+        }
+    } else if count < params.number_of_leaders {
+        // The oldest boids lead, since the newest are removed first.
+        let mut followers: Vec<(u64, Entity)> = followers.iter().map(|(e, i)| (i.0, e)).collect();
+        followers.sort_unstable();
+        for &(_, e) in followers.iter().take(params.number_of_leaders - count) {
+            commands.entity(e).insert(Leader {
+                phase: rng.0.gen_range(0.0..2.0 * PI),
+            });
+        }
+    }
+}
+
+// Moves the leaders along their path. The others are drawn to them, see perceive().
+fn lead(
+    time: Res<Time>,
+    params: Res<Parameters>,
+    window: Query<&Window, With<PrimaryWindow>>,
+    camera: Query<(&Camera, &GlobalTransform)>,
+    mut query: Query<(&Transform, &mut Boid, &Leader)>,
+) {
+    let cursor = match (window.get_single(), camera.get_single()) {
+        (Ok(window), Ok((camera, camera_transform))) => {
+            cursor_position(window, camera, camera_transform)
+        }
+        _ => None,
+    };
+    for (t, mut boid, leader) in &mut query {
+        let (min_speed, max_speed) = params.speed_limits(&boid);
+        boid.velocity = match (params.leader_path, cursor) {
+            (LeaderPath::Mouse, Some(cursor)) => {
+                let species = params.species(boid.species);
+                flocking::combine(
+                    boid.velocity,
+                    &[flocking::steering(
+                        params.offset(t.translation.truncate(), cursor),
+                        species.cohesion_force,
+                        params.steering_force,
+                    )],
+                    min_speed,
+                    max_speed,
+                )
+            }
+            // Turn by as much as the heading swings this step, at full speed.
+            (LeaderPath::Wave, _) => {
+                let phase = LEADER_FREQUENCY * time.elapsed_seconds() + leader.phase;
+                let turn = LEADER_SWING * LEADER_FREQUENCY * phase.cos() * time.delta_seconds();
+                Vec2::from_angle(turn)
+                    .rotate(boid.velocity)
+                    .normalize_or_zero()
+                    * max_speed
+            }
+            // Keep going while the pointer is outside the window.
+            (LeaderPath::Mouse, None) => boid.velocity,
+        };
+    }
+}
+
+fn draw_leaders(query: Query<&Transform, With<Leader>>, mut gizmos: Gizmos) {
+    for t in &query {
+        gizmos.circle_2d(t.translation.truncate(), LEADER_RING, Color::WHITE);
+    }
+}
+
+// Boids steer away from any predator they can see.
+fn flee_predators(
+    params: Res<Parameters>,
+    predators: Query<&Transform, With<Predator>>,
+    mut boids: Query<(&Transform, &mut Boid)>,
+) {
+    for (t, mut boid) in &mut boids {
+        let mut fear = Vec2::ZERO;
+        for predator in &predators {
+            let offset = params.offset(predator.translation.truncate(), t.translation.truncate());
+            let distance = offset.length();
+            if distance > params.view_distance {
+                continue;
+            }
+            fear += offset / distance.max(MIN_DISTANCE);
+        }
+        if fear == Vec2::ZERO {
+            continue;
+        }
+
+        let (min_speed, max_speed) = params.speed_limits(&boid);
+        boid.velocity = (boid.velocity
+            + params.predator_fear_force * fear.clamp_length_max(params.steering_force))
+        .clamp_length(min_speed, max_speed);
+    }
+}
+
+// Predators chase the nearest boid.
+fn hunt(
+    time: Res<Time>,
+    params: Res<Parameters>,
+    boids: Query<&Transform, (With<Boid>, Without<Predator>)>,
+    mut predators: Query<(&mut Transform, &mut Predator)>,
+) {
+    for (mut t, mut predator) in &mut predators {
+        let position = t.translation.truncate();
+        let nearest = boids
+            .iter()
+            .map(|b| params.offset(position, b.translation.truncate()))
+            .min_by(|a, b| a.length_squared().total_cmp(&b.length_squared()));
+        if let Some(offset) = nearest {
+            predator.velocity = (predator.velocity
+                + offset.normalize_or_zero() * params.steering_force * PREDATOR_AGILITY)
+                .clamp_length_max(params.predator_speed * stamina(predator.energy));
+        }
+
+        // Rotate the predator towards its velocity vector.
+        let direction = (t.rotation * Vec3::Y).truncate();
+        if let Some(target) = predator.velocity.try_normalize() {
+            t.rotate(Quat::from_rotation_arc_2d(direction, target));
+        }
+
+        t.translation.x += predator.velocity.x * time.delta_seconds();
+        t.translation.y += predator.velocity.y * time.delta_seconds();
+        keep_in_bounds(&params, &mut t.translation, &mut predator.velocity);
+    }
+}
+
+// Steers boids back towards the center when they get within the margin of an edge, more strongly
+// the closer they get to the edge.
+fn avoid_walls(params: Res<Parameters>, mut query: Query<(&Transform, &mut Boid)>) {
+    if params.boundary_mode != BoundaryMode::SoftAvoid {
+        return;
+    }
+    let half_world = params.world_size() / 2.0;
+    let margin = Vec2::splat(params.wall_margin)
+        .min(half_world)
+        .max(Vec2::splat(MIN_DISTANCE));
+    for (t, mut boid) in &mut query {
+        let position = t.translation.truncate();
+        // How deep into the margin the boid is along each axis, from 0 at its inner edge to 1 at
+        // the wall.
+        let depth =
+            ((position.abs() - (half_world - margin)) / margin).clamp(Vec2::ZERO, Vec2::ONE);
+        if depth == Vec2::ZERO {
+            continue;
+        }
+        let push = -position.signum() * depth;
+        let (min_speed, max_speed) = params.speed_limits(&boid);
+        boid.velocity = flocking::combine(
+            boid.velocity,
+            &[flocking::steering(
+                push,
+                params.wall_turn_force,
+                params.steering_force,
+            )],
+            min_speed,
+            max_speed,
+        );
+    }
+}
+
+// A boid resting on a sticky wall. It still counts as a neighbour for the others, but doesn't
+// move until it takes off again.
+#[derive(Component)]
+struct Perched {
+    // Pointing from the wall back into the window, the way it takes off in.
+    normal: Vec2,
+    time_left: f32,
+}
+
+fn handle_walls(
+    mut commands: Commands,
+    params: Res<Parameters>,
+    mut rng: ResMut<RngResource>,
+    mut query: Query<(Entity, &mut Transform, &mut Boid), Without<Perched>>,
+) {
+    let half_world = params.world_size() / 2.0;
+    for (e, mut t, mut b) in &mut query {
+        if params.boundary_mode == BoundaryMode::Sticky {
+            let position = t.translation.truncate();
+            let past = position.abs().cmpgt(half_world);
+            if past.any() {
+                // Pinned to the edge, or the corner when past both.
+                let pinned = position.clamp(-half_world, half_world);
+                t.translation = pinned.extend(t.translation.z);
+                b.velocity = Vec2::ZERO;
+                let normal = Vec2::select(past, -position.signum(), Vec2::ZERO);
+                let time_left = rng.0.gen_range(
+                    params.min_perch_time..=params.max_perch_time.max(params.min_perch_time),
+                );
+                commands.entity(e).insert(Perched {
+                    normal: normal.normalize(),
+                    time_left,
+                });
+            }
+            continue;
+        }
+
+        let velocity = b.velocity;
+        keep_in_bounds(&params, &mut t.translation, &mut b.velocity);
+        // Bouncing turns boids around at once, however fast they can turn.
+        if b.velocity != velocity {
+            t.rotation = Quat::from_rotation_arc_2d(Vec2::Y, b.velocity.normalize());
+        }
+    }
+}
+
+// Keeps perched boids still, whatever the forces on them, and sends them back into the window
+// once they've rested long enough, or as soon as the walls aren't sticky anymore.
+fn perch(
+    mut commands: Commands,
+    time: Res<Time>,
+    params: Res<Parameters>,
+    mut rng: ResMut<RngResource>,
+    mut query: Query<(Entity, &mut Transform, &mut Boid, &mut Perched)>,
+) {
+    for (e, mut t, mut boid, mut perched) in &mut query {
+        boid.velocity = Vec2::ZERO;
+        perched.time_left -= time.delta_seconds();
+        if perched.time_left > 0.0 && params.boundary_mode == BoundaryMode::Sticky {
+            continue;
+        }
+
+        // Take off inwards, but not necessarily straight away from the wall.
+        let direction =
+            (perched.normal + perched.normal.perp() * rng.0.gen_range(-1.0..1.0)).normalize();
+        let (min_speed, max_speed) = params.speed_limits(&boid);
+        boid.velocity = direction * rng.0.gen_range(min_speed..=max_speed.max(min_speed));
+        t.rotation = Quat::from_rotation_arc_2d(Vec2::Y, direction);
+        commands.entity(e).remove::<Perched>();
+    }
+}
+
+// The position of the mouse pointer in world coordinates, if it's over the window.
+fn cursor_position(
+    window: &Window,
+    camera: &Camera,
+    camera_transform: &GlobalTransform,
+) -> Option<Vec2> {
+    window
+        .cursor_position()
+        .and_then(|cursor| {
+            // The docked parameters panel may take the left of the window.
+            let origin = camera
+                .logical_viewport_rect()
+                .map_or(Vec2::ZERO, |rect| rect.min);
+            camera.viewport_to_world(camera_transform, cursor - origin)
+        })
+        .map(|ray| ray.origin.truncate())
+}
+
+fn handle_mouse(
+    window: Query<&Window, With<PrimaryWindow>>,
+    camera: Query<(&Camera, &GlobalTransform)>,
+    buttons: Res<ButtonInput<MouseButton>>,
+    keys: Res<ButtonInput<KeyCode>>,
+    tool: Res<ToolMode>,
+    params: Res<Parameters>,
+    mut query: Query<(&Transform, &mut Boid)>,
+) {
+    // Clicks spawn boids in the spawn tool mode, see use_spawn_tool().
+    if *tool != ToolMode::Flock && *tool != ToolMode::Follow {
+        return;
+    }
+    // Shift+click places obstacles and Ctrl+drag moves the center of gravity instead.
+    if keys.any_pressed([
+        KeyCode::ShiftLeft,
+        KeyCode::ShiftRight,
+        KeyCode::ControlLeft,
+        KeyCode::ControlRight,
+    ]) {
+        return;
+    }
+
+    let (camera, camera_transform) = camera.single();
+    let Some(mouse_position) = cursor_position(window.single(), camera, camera_transform) else {
+        return;
+    };
+
+    // Left click attracts, right click repels. When following, boids are always attracted.
+    let following = *tool == ToolMode::Follow;
+    let (direction, force) = match buttons.get_pressed().last() {
+        _ if following => (1.0, params.follow_force),
+        Some(MouseButton::Left) => (1.0, params.mouse_attract_force),
+        Some(MouseButton::Right) => (-1.0, params.mouse_repel_force),
+        _ => return, // No effect when neither button is pressed.
+    };
+
+    let radius = params.mouse_radius;
+    for (t, mut boid) in &mut query {
+        let position = t.translation.truncate();
+        let distance = position.distance(mouse_position);
+        if distance > radius {
+            continue;
+        }
+        // Close boids respond strongly, far ones barely at all. Following pulls all of them the
+        // same, so the whole flock comes along.
+        let falloff = if following {
+            1.0
+        } else {
+            1.0 - distance / radius
+        };
+        let target = (mouse_position - position).normalize_or_zero() * direction * falloff;
+
+        let (min_speed, max_speed) = params.speed_limits(&boid);
+        boid.velocity = flocking::combine(
+            boid.velocity,
+            &[flocking::steering(target, force, params.steering_force)],
+            min_speed,
+            max_speed,
+        );
+    }
+}
+
+// Left click spawns a boid, right click removes the closest one. The number of boids follows along
+// so they aren't added or removed again to match it.
+#[allow(clippy::too_many_arguments)]
+fn use_spawn_tool(
+    mut contexts: EguiContexts,
+    window: Query<&Window, With<PrimaryWindow>>,
+    camera: Query<(&Camera, &GlobalTransform)>,
+    buttons: Res<ButtonInput<MouseButton>>,
+    tool: Res<ToolMode>,
+    mut params: ResMut<Parameters>,
+    mut commands: Commands,
+    mesh: Res<BoidMesh>,
+    mut materials: ResMut<Assets<ColorMaterial>>,
+    mut rng: ResMut<RngResource>,
+    mut counter: ResMut<SpawnCounter>,
+    query: Query<(Entity, &Transform), With<Boid>>,
+) {
+    if *tool != ToolMode::Spawn || contexts.ctx_mut().is_pointer_over_area() {
+        return;
+    }
+    let (camera, camera_transform) = camera.single();
+    let Some(mouse_position) = cursor_position(window.single(), camera, camera_transform) else {
+        return;
+    };
+
+    if buttons.just_pressed(MouseButton::Left) {
+        let hue = rng.0.gen_range(0.0..360.0);
+        spawn_boid(
+            &params,
+            &mut commands,
+            Some((&*mesh, &mut *materials)),
+            &mut rng.0,
+            &mut counter,
+            Some(mouse_position),
+            None,
+            None,
+            hue,
+        );
+        params.number_of_boids += 1;
+    } else if buttons.just_pressed(MouseButton::Right) {
+        let closest = query
+            .iter()
+            .map(|(e, t)| (e, t.translation.truncate().distance(mouse_position)))
+            .filter(|&(_, distance)| distance <= SPAWN_TOOL_RADIUS)
+            .min_by(|(_, a), (_, b)| a.total_cmp(b));
+        if let Some((e, _)) = closest {
+            commands.entity(e).despawn();
+            params.number_of_boids = params.number_of_boids.saturating_sub(1);
+        }
+    }
+}
+
+// The boid being inspected.
+#[derive(Resource, Default)]
+struct Selected(Option<Entity>);
+
+// Selects the boid closest to where the window is clicked.
+fn use_select_tool(
+    mut contexts: EguiContexts,
+    window: Query<&Window, With<PrimaryWindow>>,
+    camera: Query<(&Camera, &GlobalTransform)>,
+    buttons: Res<ButtonInput<MouseButton>>,
+    tool: Res<ToolMode>,
+    mut selected: ResMut<Selected>,
+    query: Query<(Entity, &Transform), With<Boid>>,
+) {
+    if *tool != ToolMode::Select
+        || !buttons.just_pressed(MouseButton::Left)
+        || contexts.ctx_mut().is_pointer_over_area()
+    {
+        return;
+    }
+    let (camera, camera_transform) = camera.single();
+    let Some(mouse_position) = cursor_position(window.single(), camera, camera_transform) else {
+        return;
+    };
+
+    selected.0 = query
+        .iter()
+        .min_by(|(_, a), (_, b)| {
+            let a = a.translation.truncate().distance_squared(mouse_position);
+            let b = b.translation.truncate().distance_squared(mouse_position);
+            a.total_cmp(&b)
+        })
+        .map(|(e, _)| e);
+}
+
+// Outlines the selected boid, and shows what it's currently feeling in a side panel.
+fn inspect_selected(
+    mut contexts: EguiContexts,
+    params: Res<Parameters>,
+    mut selected: ResMut<Selected>,
+    query: Query<(&Transform, &Boid, &Calculations)>,
+    mut gizmos: Gizmos,
+) {
+    let Some(e) = selected.0 else {
+        return;
+    };
+    let Ok((t, boid, c)) = query.get(e) else {
+        selected.0 = None; // It was removed.
+        return;
+    };
+
+    gizmos.circle_2d(t.translation.truncate(), t.scale.x * 3.0, Color::WHITE);
+
+    let [cohesion, separation, alignment, center] = forces(&params, c, boid);
+    egui::SidePanel::right("inspector").show(contexts.ctx_mut(), |ui| {
+        ui.heading("Selected boid");
+        egui::Grid::new("inspector").show(ui, |ui| {
+            let mut row = |name: &str, value: String| {
+                ui.label(name);
+                ui.label(value);
+                ui.end_row();
+            };
+            row(
+                "Velocity",
+                format!("({:.1}, {:.1})", boid.velocity.x, boid.velocity.y),
+            );
+            row("Speed", format!("{:.1}", boid.velocity.length()));
+            row("Weight", format!("{:.2}", boid.weight));
+            row("Energy", format!("{:.0}%", boid.energy * 100.0));
+            row("Age", format!("{:.1} s", boid.age));
+            row("Species", format!("{}", boid.species));
+            row("Neighbours", format!("{}", c.neighbours));
+            row("Cohesion", format!("{:.2}", cohesion.length()));
+            row("Separation", format!("{:.2}", separation.length()));
+            row("Alignment", format!("{:.2}", alignment.length()));
+            row("Return to center", format!("{:.2}", center.length()));
+        });
+        if ui.button("Deselect").clicked() {
+            selected.0 = None;
+        }
+    });
+}
+
+// Pushes the boids along with the wind, lighter ones more than heavier ones.
+fn blow(time: Res<Time>, params: Res<Parameters>, mut query: Query<&mut Boid>) {
+    if params.wind == Vec2::ZERO {
+        return;
+    }
+    let mut wind = params.wind;
+    if params.wind_gusts {
+        // Slowly sway the direction and strength of the wind.
+        let t = time.elapsed_seconds();
+        wind = Vec2::from_angle((t * 0.3).sin() * PI / 4.0).rotate(wind)
+            * (1.0 + 0.5 * (t * 0.7).sin());
+    }
+
+    for mut boid in &mut query {
+        let (min_speed, max_speed) = params.speed_limits(&boid);
+        boid.velocity = (boid.velocity + wind * time.delta_seconds() / boid.weight)
+            .clamp_length(min_speed, max_speed);
+    }
+}
+
+// The distance at which inverse-square gravity pulls with its nominal strength.
+const GRAVITY_DISTANCE: f32 = 100.0;
+
+// Pulls the boids towards the center of gravity.
+fn gravitate(time: Res<Time>, params: Res<Parameters>, mut query: Query<(&Transform, &mut Boid)>) {
+    if !params.gravity_enabled {
+        return;
+    }
+    for (t, mut boid) in &mut query {
+        let offset = params.offset(t.translation.truncate(), params.gravity_center);
+        let distance = offset.length().max(MIN_DISTANCE);
+        let strength = if params.gravity_inverse_square {
+            params.gravity_strength * (GRAVITY_DISTANCE / distance).powi(2)
+        } else {
+            params.gravity_strength
+        };
+        let (min_speed, max_speed) = params.speed_limits(&boid);
+        boid.velocity = (boid.velocity + offset / distance * strength * time.delta_seconds())
+            .clamp_length(min_speed, max_speed);
+    }
+}
+
+// A point that draws boids in when its strength is positive, or pushes them away when negative.
+#[derive(Component)]
+struct AttractorPoint {
+    strength: f32,
+}
+
+// How large attractor points are drawn, and how close to one a click has to be to remove it.
+const ATTRACTOR_SIZE: f32 = 8.0;
+
+// Steers the boids within reach of each attractor point towards it, or away from it for
+// repellers, more strongly the closer they are.
+fn attract(
+    params: Res<Parameters>,
+    attractors: Query<(&Transform, &AttractorPoint)>,
+    mut query: Query<(&Transform, &mut Boid)>,
+) {
+    if attractors.is_empty() {
+        return;
+    }
+    let radius = params.attractor_radius.max(MIN_DISTANCE);
+    for (t, mut boid) in &mut query {
+        let position = t.translation.truncate();
+        let mut steering = Vec::new();
+        for (attractor_transform, attractor) in &attractors {
+            let offset = params.offset(position, attractor_transform.translation.truncate());
+            let distance = offset.length();
+            if distance > radius {
+                continue;
+            }
+            let target = offset.normalize_or_zero() * attractor.strength.signum();
+            steering.push(flocking::steering(
+                target * (1.0 - distance / radius),
+                attractor.strength.abs(),
+                params.steering_force,
+            ));
+        }
+        if steering.is_empty() {
+            continue;
+        }
+        let (min_speed, max_speed) = params.speed_limits(&boid);
+        boid.velocity = flocking::combine(boid.velocity, &steering, min_speed, max_speed);
+    }
+}
+
+// In the attractors tool mode, left click places an attractor and right click a repeller. Clicking
+// on an existing one removes it.
+#[allow(clippy::too_many_arguments)]
+fn place_attractors(
+    mut contexts: EguiContexts,
+    mut commands: Commands,
+    window: Query<&Window, With<PrimaryWindow>>,
+    camera: Query<(&Camera, &GlobalTransform)>,
+    buttons: Res<ButtonInput<MouseButton>>,
+    tool: Res<ToolMode>,
+    params: Res<Parameters>,
+    attractors: Query<(Entity, &Transform), With<AttractorPoint>>,
+) {
+    if *tool != ToolMode::Attractors || contexts.ctx_mut().is_pointer_over_area() {
+        return;
+    }
+    let sign = if buttons.just_pressed(MouseButton::Left) {
+        1.0
+    } else if buttons.just_pressed(MouseButton::Right) {
+        -1.0
+    } else {
+        return;
+    };
+    let (camera, camera_transform) = camera.single();
+    let Some(position) = cursor_position(window.single(), camera, camera_transform) else {
+        return;
+    };
+
+    let clicked = attractors
+        .iter()
+        .find(|(_, t)| t.translation.truncate().distance(position) <= ATTRACTOR_SIZE);
+    match clicked {
+        Some((e, _)) => commands.entity(e).despawn(),
+        None => {
+            commands.spawn((
+                TransformBundle::from_transform(Transform::from_translation(position.extend(0.0))),
+                AttractorPoint {
+                    strength: sign * params.attractor_strength,
+                },
+            ));
+        }
+    }
+}
+
+// Attractors are green rings and repellers red ones, with a fainter ring showing how far they
+// reach.
+fn draw_attractors(
+    params: Res<Parameters>,
+    attractors: Query<(&Transform, &AttractorPoint)>,
+    mut gizmos: Gizmos,
+) {
+    for (t, attractor) in &attractors {
+        let color = if attractor.strength >= 0.0 {
+            Color::GREEN
+        } else {
+            Color::RED
+        };
+        let position = t.translation.truncate();
+        gizmos.circle_2d(position, ATTRACTOR_SIZE, color);
+        gizmos.circle_2d(position, params.attractor_radius, color.with_a(0.2));
+    }
+}
+
+// Ctrl+drag moves the center of gravity.
+fn drag_attractor(
+    window: Query<&Window, With<PrimaryWindow>>,
+    camera: Query<(&Camera, &GlobalTransform)>,
+    buttons: Res<ButtonInput<MouseButton>>,
+    keys: Res<ButtonInput<KeyCode>>,
+    mut params: ResMut<Parameters>,
+    mut gizmos: Gizmos,
+) {
+    if !params.gravity_enabled {
+        return;
+    }
+    if keys.any_pressed([KeyCode::ControlLeft, KeyCode::ControlRight])
+        && buttons.pressed(MouseButton::Left)
+    {
+        let (camera, camera_transform) = camera.single();
+        if let Some(position) = cursor_position(window.single(), camera, camera_transform) {
+            params.gravity_center = position;
+        }
+    }
+    gizmos.circle_2d(params.gravity_center, 4.0, Color::YELLOW);
+}
+
+fn avoid_obstacles(
+    params: Res<Parameters>,
+    obstacles: Query<(&Transform, &Obstacle)>,
+    mut boids: Query<(&Transform, &mut Boid)>,
+) {
+    for (t, mut boid) in &mut boids {
+        let position = t.translation.truncate();
+        let mut avoidance = Vec2::ZERO;
+        for (obstacle_transform, obstacle) in &obstacles {
+            // Across the edge when wrapping around them, like neighbouring boids.
+            let offset = params.offset(obstacle_transform.translation.truncate(), position);
+
+            // Turn aside well before flying into the obstacle, more urgently the sooner that would
+            // be. Boids pass on whichever side of its center they're on, so the flock splits
+            // around it and joins up again behind it.
+            if let Some(time) = obstacle
+                .ray_hit(offset, boid.velocity)
+                .filter(|&time| time < params.lookahead_time)
+            {
+                let side = boid.velocity.perp();
+                let side = if side.dot(offset) < 0.0 { -side } else { side };
+                let urgency = 1.0 - time / params.lookahead_time;
+                avoidance += side.normalize_or_zero() * params.steering_force * urgency;
+            }
+
+            let away = offset - obstacle.closest_point(offset);
+            let distance = away.length().max(MIN_DISTANCE);
+            if distance > params.view_distance {
+                continue;
+            }
+            // Away from the closest point of the surface, or from the center once inside.
+            let direction = if away == Vec2::ZERO { offset } else { away };
+            // Steer away more strongly closer to the surface, like separation from other boids.
+            avoidance += direction.normalize_or_zero() * params.view_distance / distance.powi(2);
+        }
+        if avoidance == Vec2::ZERO {
+            continue;
+        }
+
+        let (min_speed, max_speed) = params.speed_limits(&boid);
+        boid.velocity = (boid.velocity
+            + params.obstacle_force * avoidance.clamp_length_max(params.steering_force))
+        .clamp_length(min_speed, max_speed);
+    }
+}
+
+// Bounces boids off obstacles they'd otherwise fly through, which fast ones can do within a
+// single step when the obstacle is thin.
+fn collide_with_obstacles(
+    time: Res<Time>,
+    params: Res<Parameters>,
+    obstacles: Query<(&Transform, &Obstacle)>,
+    mut boids: Query<(&mut Transform, &mut Boid), Without<Obstacle>>,
+) {
+    for (mut t, mut boid) in &mut boids {
+        let to = t.translation.truncate();
+        let displacement = boid.velocity * time.delta_seconds();
+        let from = to - displacement;
+        for (obstacle_transform, obstacle) in &obstacles {
+            // Relative to the obstacle, across the edge when wrapping around them.
+            let from_center = params.offset(obstacle_transform.translation.truncate(), from);
+            let to_center = from_center + displacement;
+            let outside = from_center - obstacle.closest_point(from_center);
+            // Boids already inside are left to steer their way out.
+            if outside == Vec2::ZERO || !obstacle.intersects(from_center, to_center) {
+                continue;
+            }
+            // Stay where it was, heading away from the surface.
+            t.translation = from.extend(t.translation.z);
+            let normal = outside.normalize();
+            if boid.velocity.dot(normal) < 0.0 {
+                let velocity = boid.velocity;
+                boid.velocity = velocity - 2.0 * velocity.dot(normal) * normal;
+                t.rotation = Quat::from_rotation_arc_2d(Vec2::Y, boid.velocity.normalize());
+            }
+            break;
+        }
+    }
+}
+
+// Shift+click places an obstacle under the mouse pointer.
+#[allow(clippy::too_many_arguments)]
+fn place_obstacles(
+    mut commands: Commands,
+    mut meshes: ResMut<Assets<Mesh>>,
+    mut materials: ResMut<Assets<ColorMaterial>>,
+    window: Query<&Window, With<PrimaryWindow>>,
+    camera: Query<(&Camera, &GlobalTransform)>,
+    buttons: Res<ButtonInput<MouseButton>>,
+    keys: Res<ButtonInput<KeyCode>>,
+    params: Res<Parameters>,
+) {
+    if !keys.any_pressed([KeyCode::ShiftLeft, KeyCode::ShiftRight])
+        || !buttons.just_pressed(MouseButton::Left)
+    {
+        return;
+    }
+    let (camera, camera_transform) = camera.single();
+    if let Some(position) = cursor_position(window.single(), camera, camera_transform) {
+        let obstacle = match params.obstacle_shape {
+            ObstacleShape::Circle => Obstacle::Circle {
+                radius: params.obstacle_radius,
+            },
+            ObstacleShape::Rectangle => Obstacle::Rectangle {
+                half_extents: Vec2::new(params.obstacle_width, params.obstacle_height) / 2.0,
+            },
+        };
+        spawn_obstacle(
+            &mut commands,
+            &mut meshes,
+            &mut materials,
+            position,
+            obstacle,
+        );
+    }
+}
+
+fn spawn_obstacle(
+    commands: &mut Commands,
+    meshes: &mut Assets<Mesh>,
+    materials: &mut Assets<ColorMaterial>,
+    position: Vec2,
+    obstacle: Obstacle,
+) {
+    commands.spawn((
+        obstacle_bundle(
+            meshes,
+            materials,
+            Transform::from_translation(position.extend(-1.0)),
+            &obstacle,
+        ),
+        obstacle,
+    ));
+}
+
+fn obstacle_bundle(
+    meshes: &mut Assets<Mesh>,
+    materials: &mut Assets<ColorMaterial>,
+    transform: Transform,
+    obstacle: &Obstacle,
+) -> MaterialMesh2dBundle<ColorMaterial> {
+    MaterialMesh2dBundle {
+        mesh: obstacle_mesh(meshes, obstacle),
+        material: materials.add(Color::GRAY),
+        transform,
+        ..default()
+    }
+}
+
+fn obstacle_mesh(meshes: &mut Assets<Mesh>, obstacle: &Obstacle) -> Mesh2dHandle {
+    Mesh2dHandle(match *obstacle {
+        Obstacle::Circle { radius } => meshes.add(Circle::new(radius)),
+        Obstacle::Rectangle { half_extents } => {
+            meshes.add(Rectangle::new(half_extents.x * 2.0, half_extents.y * 2.0))
+        }
+    })
+}
+
+// In the obstacles tool mode, dragging an obstacle with the left mouse button moves it and the
+// mouse wheel resizes the one under the pointer.
+#[allow(clippy::too_many_arguments)]
+fn edit_obstacles(
+    mut contexts: EguiContexts,
+    window: Query<&Window, With<PrimaryWindow>>,
+    camera: Query<(&Camera, &GlobalTransform)>,
+    buttons: Res<ButtonInput<MouseButton>>,
+    mut wheel: EventReader<MouseWheel>,
+    tool: Res<ToolMode>,
+    mut meshes: ResMut<Assets<Mesh>>,
+    mut query: Query<(Entity, &mut Transform, &mut Obstacle, &mut Mesh2dHandle)>,
+    // The obstacle being dragged, and where it was grabbed relative to its center.
+    mut grabbed: Local<Option<(Entity, Vec2)>>,
+) {
+    if *tool != ToolMode::Obstacles || !buttons.pressed(MouseButton::Left) {
+        *grabbed = None;
+    }
+    if *tool != ToolMode::Obstacles || contexts.ctx_mut().is_pointer_over_area() {
+        wheel.clear();
+        return;
+    }
+    let (camera, camera_transform) = camera.single();
+    let Some(mouse_position) = cursor_position(window.single(), camera, camera_transform) else {
+        wheel.clear();
+        return;
+    };
+
+    // The obstacle with its edge closest to the pointer, if it's close enough to grab.
+    let closest = query
+        .iter()
+        .map(|(e, t, obstacle, _)| {
+            let offset = mouse_position - t.translation.truncate();
+            (
+                e,
+                offset,
+                (offset - obstacle.closest_point(offset)).length(),
+            )
+        })
+        .filter(|&(_, _, distance)| distance <= GRAB_RADIUS)
+        .min_by(|a, b| a.2.total_cmp(&b.2))
+        .map(|(e, offset, _)| (e, offset));
+
+    if buttons.just_pressed(MouseButton::Left) {
+        *grabbed = closest;
+    }
+    if let Some((e, offset)) = *grabbed {
+        if let Ok((_, mut t, _, _)) = query.get_mut(e) {
+            let position = mouse_position - offset;
+            t.translation.x = position.x;
+            t.translation.y = position.y;
+        }
+    }
+
+    let lines: f32 = wheel
+        .read()
+        .map(|event| match event.unit {
+            MouseScrollUnit::Line => event.y,
+            MouseScrollUnit::Pixel => event.y / 100.0,
+        })
+        .sum();
+    let Some((e, _)) = grabbed.or(closest) else {
+        return;
+    };
+    if lines == 0.0 {
+        return;
+    }
+    if let Ok((_, _, mut obstacle, mut mesh)) = query.get_mut(e) {
+        let scale = 1.0 + lines * 0.1;
+        *obstacle = match *obstacle {
+            Obstacle::Circle { radius } => Obstacle::Circle {
+                radius: (radius * scale).max(1.0),
+            },
+            Obstacle::Rectangle { half_extents } => Obstacle::Rectangle {
+                half_extents: (half_extents * scale).max(Vec2::ONE),
+            },
+        };
+        *mesh = obstacle_mesh(&mut meshes, &obstacle);
+    }
+}
+
+fn predator_bundle(
+    meshes: &mut Assets<Mesh>,
+    materials: &mut Assets<ColorMaterial>,
+    transform: Transform,
+) -> MaterialMesh2dBundle<ColorMaterial> {
+    MaterialMesh2dBundle {
+        mesh: Mesh2dHandle(meshes.add(Triangle2d::new(
+            Vec2::Y * PREDATOR_SIZE * 2.0,
+            Vec2::new(-PREDATOR_SIZE, -PREDATOR_SIZE),
+            Vec2::new(PREDATOR_SIZE, -PREDATOR_SIZE),
+        ))),
+        material: materials.add(Color::RED),
+        transform,
+        ..default()
+    }
+}
+
+// Obstacles and predators loaded from a scene only come with their transform, so give them
+// everything else they need.
+#[allow(clippy::type_complexity)]
+fn complete_loaded_entities(
+    mut commands: Commands,
+    mut meshes: ResMut<Assets<Mesh>>,
+    mut materials: ResMut<Assets<ColorMaterial>>,
+    obstacles: Query<(Entity, &Transform, &Obstacle), Without<Handle<ColorMaterial>>>,
+    predators: Query<(Entity, &Transform), (With<Predator>, Without<Handle<ColorMaterial>>)>,
+) {
+    for (e, &transform, obstacle) in &obstacles {
+        commands.entity(e).insert(obstacle_bundle(
+            &mut meshes,
+            &mut materials,
+            transform,
+            obstacle,
+        ));
+    }
+    for (e, &transform) in &predators {
+        commands.entity(e).insert((
+            predator_bundle(&mut meshes, &mut materials, transform),
+            Interpolated::new(transform),
+        ));
+    }
+}
+
+// The average position of all the boids, updated every step.
+#[derive(Resource, Default)]
+struct Centroid(Vec2);
+
+fn find_centroid(mut centroid: ResMut<Centroid>, query: Query<&Transform, With<Boid>>) {
+    if query.is_empty() {
+        return;
+    }
+    centroid.0 =
+        query.iter().map(|t| t.translation.truncate()).sum::<Vec2>() / query.iter().count() as f32;
+}
+
+// The places the flock heads to in turn.
+#[derive(Resource, Default)]
+struct Waypoints {
+    points: Vec<Vec2>,
+    current: usize,
+}
+
+fn load_scenario(
+    scenario: Option<Res<Scenario>>,
+    mut commands: Commands,
+    mut meshes: ResMut<Assets<Mesh>>,
+    mut materials: ResMut<Assets<ColorMaterial>>,
+) {
+    let Some(scenario) = scenario else {
+        return;
+    };
+    for obstacle in &scenario.obstacles {
+        let shape = match obstacle.half_extents {
+            Some(half_extents) => Obstacle::Rectangle { half_extents },
+            None => Obstacle::Circle {
+                radius: obstacle.radius,
+            },
+        };
+        spawn_obstacle(
+            &mut commands,
+            &mut meshes,
+            &mut materials,
+            obstacle.position,
+            shape,
+        );
+    }
+    commands.insert_resource(Waypoints {
+        points: scenario.waypoints.clone(),
+        current: 0,
+    });
+}
+
+// Steers the boids towards the current waypoint, moving on to the next one once the center of
+// the flock gets close to it.
+fn follow_waypoints(
+    params: Res<Parameters>,
+    centroid: Res<Centroid>,
+    mut waypoints: ResMut<Waypoints>,
+    mut query: Query<(&Transform, &mut Boid)>,
+) {
+    if waypoints.points.is_empty() || query.is_empty() {
+        return;
+    }
+
+    if centroid.0.distance(waypoints.points[waypoints.current]) < params.view_distance {
+        waypoints.current = (waypoints.current + 1) % waypoints.points.len();
+    }
+    let target = waypoints.points[waypoints.current];
+
+    for (t, mut boid) in &mut query {
+        let direction = target - t.translation.truncate();
+        let (min_speed, max_speed) = params.speed_limits(&boid);
+        boid.velocity = flocking::combine(
+            boid.velocity,
+            &[flocking::steering(
+                direction,
+                params.waypoint_force,
+                params.steering_force,
+            )],
+            min_speed,
+            max_speed,
+        );
+    }
+}
+
+fn draw_waypoints(params: Res<Parameters>, waypoints: Res<Waypoints>, mut gizmos: Gizmos) {
+    for (i, &point) in waypoints.points.iter().enumerate() {
+        let color = if i == waypoints.current {
+            Color::GREEN
+        } else {
+            Color::DARK_GREEN
+        };
+        gizmos.circle_2d(point, params.view_distance, color);
+    }
+}
+
+// How far the camera can zoom in and out.
+const MIN_ZOOM: f32 = 0.1;
+const MAX_ZOOM: f32 = 10.0;
+
+// The mouse wheel zooms, dragging with the middle button pans and 0 resets the camera. In the
+// obstacles tool mode, the mouse wheel resizes obstacles instead, see edit_obstacles().
+fn control_camera(
+    mut contexts: EguiContexts,
+    tool: Res<ToolMode>,
+    mut wheel: EventReader<MouseWheel>,
+    mut motion: EventReader<MouseMotion>,
+    buttons: Res<ButtonInput<MouseButton>>,
+    keys: Res<ButtonInput<KeyCode>>,
+    mut camera: Query<(&Camera, &mut Transform, &mut OrthographicProjection)>,
+) {
+    let (camera, mut transform, mut projection) = camera.single_mut();
+    // Unless it's a digit being typed into the parameters window.
+    if keys.just_pressed(KeyCode::Digit0) && !contexts.ctx_mut().wants_keyboard_input() {
+        *transform = Transform::default();
+        projection.scale = 1.0;
+    }
+
+    // Leave the mouse to the parameters window when it's over it.
+    if contexts.ctx_mut().is_pointer_over_area() {
+        wheel.clear();
+        motion.clear();
+        return;
+    }
+
+    for event in wheel.read() {
+        if *tool == ToolMode::Obstacles {
+            continue;
+        }
+        let lines = match event.unit {
+            MouseScrollUnit::Line => event.y,
+            MouseScrollUnit::Pixel => event.y / 100.0,
+        };
+        projection.scale = (projection.scale * (1.0 - lines * 0.1)).clamp(MIN_ZOOM, MAX_ZOOM);
+    }
+
+    let drag: Vec2 = motion.read().map(|event| event.delta).sum();
+    if buttons.pressed(MouseButton::Middle) {
+        // How much of the world a pixel covers, which also depends on how the world fits the
+        // window.
+        let scale = camera
+            .logical_viewport_size()
+            .map_or(projection.scale, |size| {
+                projection.area.width() / size.x.max(1.0)
+            });
+        // Screen coordinates go down while world coordinates go up.
+        transform.translation.x -= drag.x * scale;
+        transform.translation.y += drag.y * scale;
+    }
+}
+
+// A small overview of the whole world, to keep track of things when zoomed in.
+#[derive(Resource, Default)]
+struct Minimap {
+    enabled: bool,
+}
+
+// How wide the minimap is, in points.
+const MINIMAP_WIDTH: f32 = 160.0;
+
+#[allow(clippy::type_complexity)]
+fn draw_minimap(
+    mut contexts: EguiContexts,
+    minimap: Res<Minimap>,
+    params: Res<Parameters>,
+    materials: Res<Assets<ColorMaterial>>,
+    camera: Query<(&GlobalTransform, &OrthographicProjection), With<Camera>>,
+    boids: Query<(&Transform, Option<&Handle<ColorMaterial>>, Option<&Sprite>), With<Boid>>,
+) {
+    if !minimap.enabled {
+        return;
+    }
+    let world_size = params.world_size();
+    let map_size = egui::vec2(
+        MINIMAP_WIDTH,
+        MINIMAP_WIDTH * world_size.y / world_size.x.max(1.0),
+    );
+    let (camera_transform, projection) = camera.single();
+
+    egui::Area::new(egui::Id::new("minimap"))
+        .anchor(egui::Align2::RIGHT_BOTTOM, [-10.0, -10.0])
+        .show(contexts.ctx_mut(), |ui| {
+            let (response, painter) = ui.allocate_painter(map_size, egui::Sense::hover());
+            let rect = response.rect;
+            // World coordinates go up from the center, screen coordinates down from the corner.
+            let to_map = |p: Vec2| {
+                let t = (p + world_size / 2.0) / world_size;
+                rect.left_bottom() + egui::vec2(t.x * rect.width(), -t.y * rect.height())
+            };
+
+            painter.rect_filled(rect, 2.0, egui::Color32::from_black_alpha(180));
+            for (t, material_handle, sprite) in &boids {
+                let color = boid_color(&materials, material_handle, sprite).unwrap_or(Color::WHITE);
+                let [r, g, b, a] = color.as_rgba_u8();
+                painter.circle_filled(
+                    to_map(t.translation.truncate()),
+                    1.0,
+                    egui::Color32::from_rgba_unmultiplied(r, g, b, a),
+                );
+            }
+
+            // The part of the window the camera shows.
+            let center = camera_transform.translation().truncate();
+            let view = egui::Rect::from_two_pos(
+                to_map(center + projection.area.min),
+                to_map(center + projection.area.max),
+            );
+            painter.rect_stroke(view, 0.0, egui::Stroke::new(1.0, egui::Color32::WHITE));
+        });
+}
+
+fn move_spotlight(
+    window: Query<&Window, With<PrimaryWindow>>,
+    camera: Query<(&Camera, &GlobalTransform)>,
+    mut spotlight: ResMut<Spotlight>,
+    mut gizmos: Gizmos,
+) {
+    if !spotlight.enabled {
+        return;
+    }
+    if spotlight.follow_cursor {
+        let (camera, camera_transform) = camera.single();
+        if let Some(position) = cursor_position(window.single(), camera, camera_transform) {
+            spotlight.center = position;
+        }
+    }
+
+    // Outline the region so it's clear what's being highlighted.
+    let color = Color::rgba(1.0, 1.0, 1.0, 0.2);
+    match spotlight.shape {
+        SpotlightShape::Circle => {
+            gizmos.circle_2d(spotlight.center, spotlight.size, color);
+        }
+        SpotlightShape::Rectangle => {
+            gizmos.rect_2d(
+                spotlight.center,
+                0.0,
+                Vec2::splat(spotlight.size * 2.0),
+                color,
+            );
+        }
+    }
+}
+
+fn fly(
+    time: Res<Time>,
+    params: Res<Parameters>,
+    mut query: Query<(&mut Transform, &mut Boid, &mut Trail), Without<Perched>>,
+) {
+    let max_angle = params.max_turn_rate.to_radians() * time.delta_seconds();
+    for (mut transform, mut boid, mut trail) in &mut query {
+        let direction = (transform.rotation * Vec3::Y).truncate();
+        // Boids standing still keep facing the same way.
+        let target = boid.velocity.try_normalize().unwrap_or(direction);
+
+        // Rotate boid towards its velocity vector, no faster than it can turn, then fly the way
+        // it's facing.
+        let angle = direction.angle_between(target).clamp(-max_angle, max_angle);
+        transform.rotate(Quat::from_rotation_z(angle));
+        boid.velocity = Vec2::from_angle(angle).rotate(direction) * boid.velocity.length();
+
+        let displacement = boid.velocity * params.speed_multiplier * time.delta_seconds();
+        transform.translation.x += displacement.x;
+        transform.translation.y += displacement.y;
+
+        trail.positions.push_back(transform.translation.truncate());
+        while trail.positions.len() > params.trail_length {
+            trail.positions.pop_front();
+        }
+    }
+}
+
+// Moves all the boids and their trails by the same amount so the center of the flock stays in the
+// middle, which keeps how they move relative to each other.
+fn lock_centroid(
+    params: Res<Parameters>,
+    mut query: Query<(&mut Transform, &mut Trail), With<Boid>>,
+) {
+    if !params.lock_centroid || query.is_empty() {
+        return;
+    }
+    let centroid = query
+        .iter()
+        .map(|(t, _)| t.translation.truncate())
+        .sum::<Vec2>()
+        / query.iter().len() as f32;
+    for (mut t, mut trail) in &mut query {
+        t.translation.x -= centroid.x;
+        t.translation.y -= centroid.y;
+        for position in &mut trail.positions {
+            *position -= centroid;
+        }
+    }
+}
+
+// How often to warn about boids going haywire at most, in seconds.
+const NON_FINITE_WARNING_INTERVAL: f32 = 5.0;
+
+// Puts back boids whose velocity or position isn't a number anymore, which extreme parameters can
+// lead to: they get a random velocity and are brought back into the window. Otherwise they'd
+// vanish and spread NaNs to their neighbours.
+fn fix_non_finite(
+    time: Res<Time>,
+    params: Res<Parameters>,
+    mut rng: ResMut<RngResource>,
+    mut query: Query<(&mut Transform, &mut Boid)>,
+    // Since when boids were fixed without a warning, and how many.
+    mut unreported: Local<(f32, usize)>,
+) {
+    let half_world = params.world_size() / 2.0;
+    for (mut t, mut boid) in &mut query {
+        let velocity_ok = boid.velocity.is_finite();
+        let position_ok = t.translation.is_finite() && t.rotation.is_finite();
+        if velocity_ok && position_ok {
+            continue;
+        }
+        if !velocity_ok {
+            let (min_speed, max_speed) = params.speed_limits(&boid);
+            let direction = Vec2::from_angle(rng.0.gen_range(0.0..2.0 * PI));
+            boid.velocity = direction * rng.0.gen_range(min_speed..=max_speed);
+        }
+        if !position_ok {
+            let position = t.translation.truncate();
+            // Infinities clamp to the edges, and NaNs go to the middle.
+            let position = Vec2::select(position.is_nan_mask(), Vec2::ZERO, position)
+                .clamp(-half_world, half_world);
+            t.translation = position.extend(0.0);
+        }
+        t.rotation =
+            Quat::from_rotation_arc_2d(Vec2::Y, boid.velocity.try_normalize().unwrap_or(Vec2::Y));
+        unreported.1 += 1;
+    }
+
+    unreported.0 += time.delta_seconds();
+    if unreported.1 > 0 && unreported.0 >= NON_FINITE_WARNING_INTERVAL {
+        warn!(
+            "Fixed {} boids with a velocity or position that wasn't a number, the parameters may be too extreme",
+            unreported.1
+        );
+        *unreported = (0.0, 0);
+    }
+}
+
+// Pushes apart boids that ended up closer than the minimum distance, moving both by the same
+// amount. This corrects their positions only, without changing where they're heading.
+fn keep_apart(params: Res<Parameters>, mut query: Query<(Entity, &mut Transform), With<Boid>>) {
+    if params.min_distance <= 0.0 {
+        return;
+    }
+
+    let mut grid = SpatialGrid {
+        cell_size: params.min_distance,
+        cells: HashMap::new(),
+    };
+    let positions: HashMap<Entity, Vec2> = query
+        .iter()
+        .map(|(e, t)| (e, t.translation.truncate()))
+        .collect();
+    for (&e, &p) in &positions {
+        let cell = grid.cell(p);
+        grid.cells.entry(cell).or_default().push(e);
+    }
+
+    let mut corrections: HashMap<Entity, Vec2> = HashMap::new();
+    let mut candidates = Vec::new();
+    for (&e1, &p1) in &positions {
+        candidates.clear();
+        for position in params.neighbourhood(p1, params.min_distance) {
+            candidates.extend(grid.neighbours(position));
+        }
+        candidates.sort_unstable();
+        candidates.dedup();
+        // Each pair only once.
+        for &e2 in candidates.iter().filter(|&&e2| e2 > e1) {
+            let offset = params.offset(positions[&e2], p1);
+            let distance = offset.length();
+            if distance >= params.min_distance {
+                continue;
+            }
+            let direction = if offset == Vec2::ZERO {
+                overlap_direction(e2, e1)
+            } else {
+                offset / distance
+            };
+            let push = direction * (params.min_distance - distance) / 2.0;
+            *corrections.entry(e1).or_default() += push;
+            *corrections.entry(e2).or_default() -= push;
+        }
+    }
+
+    for (e, correction) in corrections {
+        if let Ok((_, mut t)) = query.get_mut(e) {
+            t.translation += correction.extend(0.0);
+        }
+    }
+}
+
+// How many neighbours it takes for a boid to be colored as crowded.
+const CROWDED: f32 = 20.0;
+
+#[allow(clippy::type_complexity)]
+fn recolor(
+    params: Res<Parameters>,
+    spotlight: Res<Spotlight>,
+    mut materials: ResMut<Assets<ColorMaterial>>,
+    mut query: Query<(
+        &Transform,
+        Option<&Handle<ColorMaterial>>,
+        Option<&mut Sprite>,
+        &Boid,
+        &Calculations,
+        &Hue,
+        Option<&PaletteColor>,
+    )>,
+) {
+    // Boids keeping their hue only need recoloring when the colors change, or as they age.
+    if !params.recolor_by_heading
+        && params.max_age <= 0.0
+        && !params.is_changed()
+        && !spotlight.is_changed()
+    {
+        return;
+    }
+    for (transform, material_handle, sprite, boid, calculations, spawn_hue, palette_color) in
+        &mut query
+    {
+        let Some(color) = boid_color_mut(&mut materials, material_handle, sprite) else {
+            continue;
+        };
+
+        // Gradients go from blue for the lowest values to red for the highest.
+        let hue = match params.color_mode {
+            _ if !params.recolor_by_heading => spawn_hue.0,
+            // Color the boid based on its species, or its velocity angle when there's only one.
+            ColorMode::Heading if params.number_of_species > 1 => {
+                SPECIES_HUES[boid.species as usize]
+            }
+            ColorMode::Heading => {
+                // Perched boids aren't going anywhere, so go by the way they're facing.
+                let heading = boid
+                    .velocity
+                    .try_normalize()
+                    .unwrap_or_else(|| (transform.rotation * Vec3::Y).truncate());
+                let angle = heading.angle_between(Vec2::Y);
+                360. * (angle + PI) / (2.0 * PI)
+            }
+            ColorMode::Speed => {
+                let speed = boid.velocity.length();
+                let species = params.species(boid.species);
+                let t = (speed - species.min_speed) / (species.max_speed - species.min_speed);
+                240. * (1.0 - t.clamp(0.0, 1.0))
+            }
+            ColorMode::Neighbours => {
+                let t = calculations.neighbours as f32 / CROWDED;
+                240. * (1.0 - t.clamp(0.0, 1.0))
+            }
+        };
+
+        // Boids keeping their color from the palette keep its saturation and lightness too.
+        let (hue, saturation, lightness) = match palette_color {
+            Some(PaletteColor(color)) if !params.recolor_by_heading => {
+                let [hue, saturation, lightness, _] = color.as_hsla_f32();
+                (hue, saturation, lightness)
+            }
+            _ => (hue, params.boid_saturation, params.boid_lightness),
+        };
+
+        // Paler with age, down to half as colorful at the end of their life, and dimmed outside
+        // the spotlight.
+        let age = if params.max_age > 0.0 {
+            (boid.age / params.max_age).min(1.0)
+        } else {
+            0.0
+        };
+        *color = Color::hsl(
+            hue,
+            saturation * (1.0 - age / 2.0),
+            lightness * spotlight.brightness(transform.translation.truncate()),
+        );
+    }
+}
+
+// Fades boids in and out, despawning them once they've faded out. This is part of the simulation
+// step, as boids still flock while they fade, so they go at the same step whatever the frame rate.
+#[allow(clippy::type_complexity)]
+fn fade(
+    mut commands: Commands,
+    time: Res<Time>,
+    mut materials: ResMut<Assets<ColorMaterial>>,
+    mut query: Query<(
+        Entity,
+        Option<&Handle<ColorMaterial>>,
+        Option<&mut Sprite>,
+        &mut Fade,
+    )>,
+) {
+    let change = time.delta_seconds() / FADE_TIME;
+    for (e, material_handle, sprite, mut fade) in &mut query {
+        fade.alpha = if fade.out {
+            fade.alpha - change
+        } else {
+            fade.alpha + change
+        }
+        .clamp(0.0, 1.0);
+        if let Some(color) = boid_color_mut(&mut materials, material_handle, sprite) {
+            color.set_a(fade.alpha);
+        }
+        if fade.out && fade.alpha == 0.0 {
+            commands.entity(e).despawn();
+        } else if !fade.out && fade.alpha == 1.0 {
+            commands.entity(e).remove::<Fade>();
+        }
+    }
+}
+
+// Keeps the boids fading in or out as transparent as they are. This runs after recolor(), which
+// sets their colors as if they were fully opaque.
+#[allow(clippy::type_complexity)]
+fn show_fade(
+    mut materials: ResMut<Assets<ColorMaterial>>,
+    mut query: Query<(Option<&Handle<ColorMaterial>>, Option<&mut Sprite>, &Fade)>,
+) {
+    for (material_handle, sprite, fade) in &mut query {
+        if let Some(color) = boid_color_mut(&mut materials, material_handle, sprite) {
+            color.set_a(fade.alpha);
+        }
+    }
+}
+
+#[allow(clippy::type_complexity)]
+fn draw_trails(
+    params: Res<Parameters>,
+    materials: Res<Assets<ColorMaterial>>,
+    query: Query<(&Trail, Option<&Handle<ColorMaterial>>, Option<&Sprite>)>,
+    mut gizmos: Gizmos,
+) {
+    if params.trail_length == 0 || params.trail_mesh {
+        return;
+    }
+    let half_world = params.world_size() / 2.0;
+    for (trail, material_handle, sprite) in &query {
+        let Some(color) = boid_color(&materials, material_handle, sprite) else {
+            continue;
+        };
+        let positions = &trail.positions;
+        for (i, (from, to)) in positions.iter().zip(positions.iter().skip(1)).enumerate() {
+            // Don't draw a streak across the window where the boid went past an edge.
+            if (*to - *from).abs().cmpgt(half_world).any() {
+                continue;
+            }
+            // Fade out towards the oldest positions.
+            let alpha = (i + 1) as f32 / positions.len() as f32;
+            gizmos.line_2d(*from, *to, color.with_a(alpha));
+        }
+    }
+}
+
+// How many pixels of the long exposure there are per unit of the world. Fewer than on screen, to
+// keep fading it cheap.
+const EXPOSURE_RESOLUTION: f32 = 0.5;
+
+// A long exposure of the flock, drawn behind it: the boids leave their color on an image that
+// dims a little every frame instead of being cleared, so their paths build up into streaks of any
+// length for the price of one image.
+#[derive(Component)]
+struct Exposure {
+    size: UVec2,
+    // How bright each pixel still is, from 1 when a boid just went over it.
+    intensity: Vec<f32>,
+    // Where each boid was last frame, to paint the whole way it went since.
+    last: HashMap<Entity, Vec2>,
+}
+
+#[allow(clippy::too_many_arguments, clippy::type_complexity)]
+fn expose(
+    mut commands: Commands,
+    time: Res<Time>,
+    state: Res<SimState>,
+    params: Res<Parameters>,
+    materials: Res<Assets<ColorMaterial>>,
+    mut images: ResMut<Assets<Image>>,
+    boids: Query<
+        (
+            Entity,
+            &Transform,
+            Option<&Handle<ColorMaterial>>,
+            Option<&Sprite>,
+        ),
+        With<Boid>,
+    >,
+    mut exposure: Query<(Entity, &mut Exposure, &Handle<Image>)>,
+) {
+    let size = (params.world_size() * EXPOSURE_RESOLUTION)
+        .ceil()
+        .as_uvec2()
+        .max(UVec2::ONE);
+    let Ok((e, mut exposure, handle)) = exposure.get_single_mut() else {
+        if params.exposure {
+            let image = Image::new_fill(
+                Extent3d {
+                    width: size.x,
+                    height: size.y,
+                    depth_or_array_layers: 1,
+                },
+                TextureDimension::D2,
+                &[0, 0, 0, 0],
+                TextureFormat::Rgba8UnormSrgb,
+                // Kept in the main world too, to paint on it.
+                RenderAssetUsages::default(),
+            );
+            commands.spawn((
+                SpriteBundle {
+                    sprite: Sprite {
+                        custom_size: Some(params.world_size()),
+                        ..default()
+                    },
+                    texture: images.add(image),
+                    // Behind the trails.
+                    transform: Transform::from_xyz(0.0, 0.0, -1.5),
+                    ..default()
+                },
+                Exposure {
+                    size,
+                    intensity: vec![0.0; (size.x * size.y) as usize],
+                    last: HashMap::new(),
+                },
+            ));
+        }
+        return;
+    };
+    // Start over when turned off, or when the world changes size.
+    if !params.exposure || exposure.size != size {
+        commands.entity(e).despawn();
+        return;
+    }
+    let Some(image) = images.get_mut(handle) else {
+        return;
+    };
+
+    // Only the opacity fades, the pixels keep the color of the last boid over them. Nothing fades
+    // while paused.
+    let exposure = &mut *exposure;
+    if !state.paused {
+        let fade = (1.0 - params.exposure_fade).powf(time.delta_seconds());
+        for (intensity, pixel) in exposure
+            .intensity
+            .iter_mut()
+            .zip(image.data.chunks_exact_mut(4))
+        {
+            *intensity *= fade;
+            pixel[3] = (*intensity * 255.0) as u8;
+        }
+    }
+
+    // Pixels go down from the top left corner, world coordinates up from the center.
+    let half_world = params.world_size() / 2.0;
+    let to_pixel =
+        |p: Vec2| Vec2::new(p.x + half_world.x, half_world.y - p.y) * EXPOSURE_RESOLUTION;
+    let mut last = HashMap::with_capacity(exposure.last.len());
+    for (e, t, material_handle, sprite) in &boids {
+        let Some(color) = boid_color(&materials, material_handle, sprite) else {
+            continue;
+        };
+        let position = t.translation.truncate();
+        last.insert(e, position);
+        let from = exposure
+            .last
+            .get(&e)
+            .copied()
+            // Don't paint a streak across the world where the boid went past an edge.
+            .filter(|from| !(position - *from).abs().cmpgt(half_world).any())
+            .unwrap_or(position);
+
+        let [r, g, b, _] = color.as_rgba_u8();
+        let (from, to) = (to_pixel(from), to_pixel(position));
+        let steps = from.distance(to).ceil().max(1.0) as usize;
+        for step in 0..=steps {
+            let p = from.lerp(to, step as f32 / steps as f32).floor();
+            if p.x < 0.0 || p.y < 0.0 || p.x >= size.x as f32 || p.y >= size.y as f32 {
+                continue;
+            }
+            let index = p.y as usize * size.x as usize + p.x as usize;
+            exposure.intensity[index] = 1.0;
+            image.data[index * 4..index * 4 + 4].copy_from_slice(&[r, g, b, 255]);
+        }
+    }
+    exposure.last = last;
+}
+
+// The trails of all the boids, as a single mesh of line segments.
+#[derive(Component)]
+struct TrailMesh;
+
+#[allow(clippy::type_complexity)]
+fn draw_trail_mesh(
+    mut commands: Commands,
+    params: Res<Parameters>,
+    mut meshes: ResMut<Assets<Mesh>>,
+    mut materials: ResMut<Assets<ColorMaterial>>,
+    query: Query<(&Trail, Option<&Handle<ColorMaterial>>, Option<&Sprite>)>,
+    mut trail_mesh: Query<(&Mesh2dHandle, &mut Visibility), With<TrailMesh>>,
+) {
+    let visible = params.trail_length > 0 && params.trail_mesh;
+    let Ok((handle, mut visibility)) = trail_mesh.get_single_mut() else {
+        if visible {
+            let mesh = Mesh::new(PrimitiveTopology::LineList, RenderAssetUsages::default());
+            commands.spawn((
+                MaterialMesh2dBundle {
+                    mesh: Mesh2dHandle(meshes.add(mesh)),
+                    // The colors of the vertices are multiplied by those of the material.
+                    material: materials.add(Color::WHITE),
+                    // Behind the boids.
+                    transform: Transform::from_xyz(0.0, 0.0, -1.0),
+                    ..default()
+                },
+                TrailMesh,
+            ));
+        }
+        return;
+    };
+    *visibility = if visible {
+        Visibility::Inherited
+    } else {
+        Visibility::Hidden
+    };
+    if !visible {
+        return;
+    }
+    let Some(mesh) = meshes.get_mut(&handle.0) else {
+        return;
+    };
+
+    let half_world = params.world_size() / 2.0;
+    let mut positions = Vec::new();
+    let mut colors = Vec::new();
+    for (trail, material_handle, sprite) in &query {
+        let Some(color) = boid_color(&materials, material_handle, sprite) else {
+            continue;
+        };
+        let points = &trail.positions;
+        for (i, (from, to)) in points.iter().zip(points.iter().skip(1)).enumerate() {
+            // Don't draw a streak across the window where the boid went past an edge.
+            if (*to - *from).abs().cmpgt(half_world).any() {
+                continue;
+            }
+            // Fade out towards the oldest positions.
+            let alpha = (i + 1) as f32 / points.len() as f32;
+            positions.extend([from.extend(0.0).to_array(), to.extend(0.0).to_array()]);
+            colors.extend([color.with_a(alpha).as_linear_rgba_f32(); 2]);
+        }
+    }
+    mesh.insert_attribute(Mesh::ATTRIBUTE_POSITION, positions);
+    mesh.insert_attribute(Mesh::ATTRIBUTE_COLOR, colors);
+}
+
+// A screenshot asked for from the parameters window, taken on the next frame.
+#[derive(Resource, Default)]
+#[cfg_attr(target_arch = "wasm32", allow(dead_code))]
+struct Capture {
+    requested: bool,
+    // Whether to leave the parameters window out of the screenshot.
+    hide_ui: bool,
+    // Whether the parameters window is hidden for this frame.
+    hiding: bool,
+    // Whether to put the parameters needed to reproduce the screenshot in a band along its bottom.
+    caption: bool,
+    // Whether the caption is shown for this frame.
+    captioning: bool,
+}
+
+// Saves the window to a PNG named after the time in the working directory.
+#[cfg(not(target_arch = "wasm32"))]
+fn take_screenshot(
+    mut capture: ResMut<Capture>,
+    mut screenshots: ResMut<bevy::render::view::screenshot::ScreenshotManager>,
+    window: Query<Entity, With<PrimaryWindow>>,
+) {
+    capture.hiding = false;
+    capture.captioning = false;
+    if !capture.requested {
+        return;
+    }
+    capture.requested = false;
+
+    let millis = std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .map(|d| d.as_millis())
+        .unwrap_or_default();
+    let path = format!("screenshot-{}.png", millis);
+    match screenshots.save_screenshot_to_disk(window.single(), &path) {
+        Ok(()) => {
+            info!("Saving screenshot to {}", path);
+            capture.hiding = capture.hide_ui;
+            capture.captioning = capture.caption;
+        }
+        Err(e) => error!("Failed to take a screenshot: {}", e),
+    }
+}
+
+// Draws the number of boids, the forces of each species and the seed over the bottom of the
+// window while a screenshot is taken, so a shared picture says how to get the same flock.
+#[cfg(not(target_arch = "wasm32"))]
+fn draw_caption(mut contexts: EguiContexts, capture: Res<Capture>, params: Res<Parameters>) {
+    if !capture.captioning {
+        return;
+    }
+    let mut caption = format!("{} boids", params.number_of_boids);
+    for species in &params.species[..params.number_of_species] {
+        caption += &format!(
+            " | cohesion {:.1}, separation {:.1}, alignment {:.1}",
+            species.cohesion_force, species.separation_force, species.alignment_force
+        );
+    }
+    caption += &format!(" | seed {}", params.seed);
+    // Over the boids rather than in a panel, which would take space from them, see fit_to_panel().
+    egui::Area::new(egui::Id::new("caption"))
+        .anchor(egui::Align2::LEFT_BOTTOM, egui::Vec2::ZERO)
+        .show(contexts.ctx_mut(), |ui| {
+            egui::Frame::none()
+                .fill(egui::Color32::from_black_alpha(192))
+                .inner_margin(6.0)
+                .show(ui, |ui| {
+                    ui.label(
+                        egui::RichText::new(caption)
+                            .color(egui::Color32::WHITE)
+                            .monospace(),
+                    );
+                });
+        });
+}
+
+#[allow(clippy::too_many_arguments)]
+fn parameters_ui(
+    mut contexts: EguiContexts,
+    diagnostics: Res<DiagnosticsStore>,
+    mut params: ResMut<Parameters>,
+    (mut spotlight, mut heatmap, mut minimap): (
+        ResMut<Spotlight>,
+        ResMut<Heatmap>,
+        ResMut<Minimap>,
+    ),
+    (mut debug, mut capture, order): (ResMut<DebugDraw>, ResMut<Capture>, Res<Order>),
+    mut tool: ResMut<ToolMode>,
+    mut rng: ResMut<RngResource>,
+    (mut state, mut scatter, mut history): (
+        ResMut<SimState>,
+        ResMut<ScatterState>,
+        ResMut<History>,
+    ),
+    mut commands: Commands,
+    mut respawn: ResMut<Respawn>,
+    #[cfg(not(target_arch = "wasm32"))] mut scene_requests: EventWriter<SceneRequest>,
+    boids: Query<(Entity, &Transform), With<Boid>>,
+    (obstacles, attractors): (
+        Query<Entity, With<Obstacle>>,
+        Query<Entity, With<AttractorPoint>>,
+    ),
+    mut selected_species: Local<usize>,
+    mut previous_params: Local<Option<Parameters>>,
+) {
+    if capture.hiding {
+        return;
+    }
+    // The widgets take the parameters mutably whether they change them or not, so they're only
+    // marked as changed when they did, for the systems only acting on changes like recolor().
+    let before = params.clone();
+    ParametersContainer::default()
+        .docked(params.dock_parameters)
+        .show(contexts.ctx_mut(), |ui| {
+            let params = params.bypass_change_detection();
+            let fps = diagnostics
+                .get(&FrameTimeDiagnosticsPlugin::FPS)
+                .and_then(|d| d.smoothed())
+                .unwrap_or_default();
+            let frame_time = diagnostics
+                .get(&FrameTimeDiagnosticsPlugin::FRAME_TIME)
+                .and_then(|d| d.smoothed())
+                .unwrap_or_default();
+            ui.label(format!(
+                "{:.0} FPS ({:.1} ms), {} boids",
+                fps,
+                frame_time,
+                boids.iter().count()
+            ));
+            ui.horizontal(|ui| {
+                ui.checkbox(&mut params.dock_parameters, "Dock")
+                    .on_hover_text("Keep the parameters in a panel on the side, next to the boids instead of over them.");
+                ui.checkbox(&mut params.compact_parameters, "Compact")
+                    .on_hover_text("Show the forces in a smaller table instead of sliders.");
+            });
+            ui.separator();
+            ui.add(
+                egui::Slider::new(&mut params.number_of_boids, 1..=MAX_BOIDS)
+                    .logarithmic(true)
+                    .text("Number of boids"),
             )
             .on_hover_text("Too many boids will affect frame rate.");
+            ui.add(
+                egui::Slider::new(&mut params.number_of_species, 1..=SPECIES_HUES.len())
+                    .text("Number of species"),
+            )
+            .on_hover_text("Boids only flock with their own species, but keep away from all others.");
+            *selected_species = (*selected_species).min(params.number_of_species - 1);
+            if params.number_of_species > 1 {
+                egui::ComboBox::from_label("Species")
+                    .selected_text(format!("Species {}", *selected_species + 1))
+                    .show_ui(ui, |ui| {
+                        for i in 0..params.number_of_species {
+                            ui.selectable_value(&mut *selected_species, i, format!("Species {}", i + 1));
+                        }
+                    })
+                    .response
+                    .on_hover_text("Which species the forces and speeds below apply to.");
+            }
+            let species = *selected_species;
             ui.separator();
             ui.add(egui::Slider::new(&mut params.view_distance, 0.0..=500.0).text("View distance"))
-                .on_hover_text("How far away each boid can see.");
+                .on_hover_text("How far away each boid can see, which caps the distances below.");
+            params.separation_distance = params.separation_distance.min(params.view_distance);
+            params.cohesion_distance = params.cohesion_distance.min(params.view_distance);
+            params.alignment_distance = params.alignment_distance.min(params.view_distance);
+            let view_distance = params.view_distance;
             ui.add(
-                egui::Slider::new(&mut params.cohesion_force, 0.0..=100.0)
-                    .logarithmic(true)
-                    .text("Cohesion force"),
+                egui::Slider::new(&mut params.separation_distance, 0.0..=view_distance)
+                    .text("Separation distance"),
             )
-            .on_hover_text("How strongly to aim towards other boids.");
+            .on_hover_text("How close other boids have to be to keep away from them.");
             ui.add(
-                egui::Slider::new(&mut params.separation_force, 0.0..=100.0)
-                    .logarithmic(true)
-                    .text("Separation force"),
+                egui::Slider::new(&mut params.cohesion_distance, 0.0..=view_distance)
+                    .text("Cohesion distance"),
             )
-            .on_hover_text("How strongly to aim away from close boids.");
-        ui.add(
-            egui::Slider::new(&mut params.separation_bias, 0.01..=10.0)
-                .logarithmic(true)
-                .text("Separation bias"),
-        )
-        .on_hover_text("How strongly should the separation force be affected by distance. Larger values means closer boids have a larger influence.");
+            .on_hover_text("How close other boids have to be to head towards them.");
             ui.add(
-                egui::Slider::new(&mut params.alignment_force, 0.0..=100.0)
-                    .logarithmic(true)
-                    .text("Alignment force"),
+                egui::Slider::new(&mut params.alignment_distance, 0.0..=view_distance)
+                    .text("Alignment distance"),
             )
-            .on_hover_text("How strongly to align with nearby boids.");
+            .on_hover_text("How close other boids have to be to align with them.");
             ui.add(
-                egui::Slider::new(&mut params.alignment_bias, 0.01..=100.0)
-                    .logarithmic(true)
-                    .text("Alignment bias"),
-            ).on_hover_text("Whether to align with boids going in a similar direction. A negative value here means to align with boids going in the opposite direction.");
+                egui::Slider::new(&mut params.alignment_min_speed, 0.0..=50.0)
+                    .text("Alignment minimum speed"),
+            )
+            .on_hover_text("How fast both boids have to go to align with each other. Below that, which way they're going is mostly noise, so they'd jitter.");
+            ui.add(egui::Slider::new(&mut params.min_distance, 0.0..=20.0).text("Minimum distance"))
+                .on_hover_text("How close boids can get to each other at all, however strong the cohesion. 0 means they can overlap.");
+            ui.add(egui::Slider::new(&mut params.fov_degrees, 30.0..=360.0).text("Field of view"))
+                .on_hover_text("How wide each boid can see, in degrees. Boids don't see what's behind them.");
+            ui.add(egui::Slider::new(&mut params.max_neighbours, 0..=32).text("Maximum neighbours"))
+                .on_hover_text("How many of the closest boids each boid flocks with, like real birds keep track of about 7 of them. 0 means all of those in view.");
+            if params.compact_parameters {
+                // The forces and biases side by side, to fit more of the parameters on screen.
+                egui::Grid::new("forces").num_columns(3).show(ui, |ui| {
+                    fn force(value: &mut f32) -> egui::DragValue<'_> {
+                        egui::DragValue::new(value).speed(0.1).clamp_range(0.0..=100.0)
+                    }
+                    ui.label("");
+                    ui.label("Force");
+                    ui.label("Bias");
+                    ui.end_row();
+                    ui.label("Cohesion").on_hover_text("How strongly to aim towards other boids.");
+                    ui.add(force(&mut params.species[species].cohesion_force));
+                    ui.end_row();
+                    ui.label("Separation").on_hover_text("How strongly to aim away from close boids, and how much more closer boids count.");
+                    ui.add(force(&mut params.species[species].separation_force));
+                    let biased = params.separation_curve != SeparationCurve::Linear;
+                    ui.add_enabled(biased, egui::DragValue::new(&mut params.separation_bias).speed(0.01).clamp_range(0.01..=10.0));
+                    ui.end_row();
+                    ui.label("Alignment").on_hover_text("How strongly to align with nearby boids, and how much more those going in a similar direction count.");
+                    ui.add(force(&mut params.species[species].alignment_force));
+                    ui.add(egui::DragValue::new(&mut params.alignment_bias).speed(0.01).clamp_range(0.01..=100.0));
+                    ui.end_row();
+                });
+                ui.add(egui::Checkbox::new(&mut params.weighted_cohesion, "Weighted cohesion"))
+                    .on_hover_text("Aim towards the average position of the other boids weighted by their weight, so heavier boids pull harder.");
+                ui.add(egui::Slider::new(&mut params.prediction_time, 0.0..=2.0).text("Prediction"))
+                    .on_hover_text("Aim for where the other boids will be this many seconds from now instead of where they are, for tighter turns in fast flocks. 0 aims for where they are. How sharply boids can turn towards it is still limited by the steering force.");
+                ui.add(egui::Slider::new(&mut params.separation_velocity_bias, 0.0..=1.0).text("Closing bias"))
+                    .on_hover_text("Keep boids flying towards each other further apart than those going the same way, to smooth out dense aligned flocks while still avoiding head-on collisions. 0 treats them all the same.");
+            } else {
+                ui.add(
+                    egui::Slider::new(&mut params.species[species].cohesion_force, 0.0..=100.0)
+                        .logarithmic(true)
+                        .text("Cohesion force"),
+                )
+                .on_hover_text("How strongly to aim towards other boids.");
+                ui.add(egui::Checkbox::new(&mut params.weighted_cohesion, "Weighted cohesion"))
+                    .on_hover_text("Aim towards the average position of the other boids weighted by their weight, so heavier boids pull harder.");
+                ui.add(egui::Slider::new(&mut params.prediction_time, 0.0..=2.0).text("Prediction"))
+                    .on_hover_text("Aim for where the other boids will be this many seconds from now instead of where they are, for tighter turns in fast flocks. 0 aims for where they are. How sharply boids can turn towards it is still limited by the steering force.");
+                ui.add(
+                    egui::Slider::new(&mut params.species[species].separation_force, 0.0..=100.0)
+                        .logarithmic(true)
+                        .text("Separation force"),
+                )
+                .on_hover_text("How strongly to aim away from close boids.");
+                ui.add_enabled(
+                    params.separation_curve != SeparationCurve::Linear,
+                    egui::Slider::new(&mut params.separation_bias, 0.01..=10.0)
+                        .logarithmic(true)
+                        .text("Separation bias"),
+                )
+                .on_hover_text("How strongly should the separation force be affected by distance. Larger values means closer boids have a larger influence.");
+                ui.add(egui::Slider::new(&mut params.separation_velocity_bias, 0.0..=1.0).text("Closing bias"))
+                    .on_hover_text("Keep boids flying towards each other further apart than those going the same way, to smooth out dense aligned flocks while still avoiding head-on collisions. 0 treats them all the same.");
+                ui.add(
+                    egui::Slider::new(&mut params.species[species].alignment_force, 0.0..=100.0)
+                        .logarithmic(true)
+                        .text("Alignment force"),
+                )
+                .on_hover_text("How strongly to align with nearby boids.");
+                ui.add(
+                    egui::Slider::new(&mut params.alignment_bias, 0.01..=100.0)
+                        .logarithmic(true)
+                        .text("Alignment bias"),
+                ).on_hover_text("Whether to align with boids going in a similar direction. A negative value here means to align with boids going in the opposite direction.");
+            }
+            egui::ComboBox::from_label("Separation curve")
+                .selected_text(match params.separation_curve {
+                    SeparationCurve::InversePower => "Inverse power",
+                    SeparationCurve::Linear => "Linear",
+                    SeparationCurve::Exponential => "Exponential",
+                })
+                .show_ui(ui, |ui| {
+                    ui.selectable_value(&mut params.separation_curve, SeparationCurve::InversePower, "Inverse power");
+                    ui.selectable_value(&mut params.separation_curve, SeparationCurve::Linear, "Linear");
+                    ui.selectable_value(&mut params.separation_curve, SeparationCurve::Exponential, "Exponential");
+                })
+                .response
+                .on_hover_text("How separation falls off with distance: very strong very close with the inverse power of the bias, gently down to nothing at the separation distance when linear, or sharper the larger the bias when exponential.");
+            egui::ComboBox::from_label("Align with")
+                .selected_text(match params.alignment_mode {
+                    AlignmentMode::SummedVelocity => "Velocity",
+                    AlignmentMode::AverageHeading => "Heading",
+                })
+                .show_ui(ui, |ui| {
+                    ui.selectable_value(&mut params.alignment_mode, AlignmentMode::SummedVelocity, "Velocity");
+                    ui.selectable_value(&mut params.alignment_mode, AlignmentMode::AverageHeading, "Heading");
+                })
+                .response
+                .on_hover_text("Whether faster boids have more influence on the alignment, or only the direction they're going in matters.");
 
             ui.add(
                 egui::Slider::new(&mut params.steering_force, 0.0..=100.0)
                     .logarithmic(true)
                     .text("Steering force"),
             ).on_hover_text("How strongly to steer when changing direction.");
+            ui.add(
+                egui::Slider::new(&mut params.max_turn_rate, 10.0..=3600.0)
+                    .logarithmic(true)
+                    .text("Maximum turn rate"),
+            )
+            .on_hover_text("How fast boids can turn, in degrees per second. Lower values make for smoother arcs.");
+            ui.add(
+                egui::Slider::new(&mut params.weight_speed_coupling, -2.0..=2.0)
+                    .text("Weight to speed"),
+            )
+            .on_hover_text("How much the speed limits scale with weight. Heavier boids are faster above 0 and slower below 0.");
+            ui.add(
+                egui::Slider::new(&mut params.cohesion_to_center, 0.0..=100.0)
+                    .logarithmic(true)
+                    .text("Return to center"),
+            )
+            .on_hover_text("How strongly boids that strayed from the flock head back towards its center.");
+            ui.add(egui::Slider::new(&mut params.center_radius, 0.0..=1000.0).text("Center radius"))
+                .on_hover_text("How far from the center of the flock boids can roam freely.");
+            ui.add(egui::Slider::new(&mut params.wander_strength, 0.0..=0.5).text("Wander"))
+                .on_hover_text("How fast boids without any neighbours turn, in turns per second, towards a heading that slowly drifts at random, so they eventually find the flock.");
             ui.separator();
-            ui.add(egui::Slider::new(&mut params.fidelity, 0.01..=1.0).text("Fidelity")).on_hover_text("How often should boids steer at all.");
+            ui.add(egui::Slider::new(&mut params.fidelity, 0.01..=1.0).text("Fidelity")).on_hover_text("The share of boids in view each boid reacts to every step, picked at random but the same for a given seed. Lower values are faster with many boids, but make the flocking noisier. At 1 every boid in view counts, which is the smoothest.");
+            ui.horizontal(|ui| {
+                ui.add(
+                    egui::Slider::new(&mut params.time_scale, 0.1..=4.0)
+                        .logarithmic(true)
+                        .text("Time scale"),
+                )
+                .on_hover_text("Slow motion below 1, fast forward above.");
+                if ui.button("Reset").clicked() {
+                    params.time_scale = 1.0;
+                }
+            });
+            ui.horizontal(|ui| {
+                ui.add(
+                    egui::Slider::new(&mut params.speed_multiplier, 0.1..=3.0)
+                        .logarithmic(true)
+                        .text("Speed multiplier"),
+                )
+                .on_hover_text("How far boids move for their speed, without changing the forces on them. Unlike the time scale, this changes how far they get between steps rather than how many steps are taken.");
+                if ui.button("Reset").clicked() {
+                    params.speed_multiplier = 1.0;
+                }
+            });
+            ui.add(egui::Slider::new(&mut params.simulation_hz, 10.0..=240.0).text("Simulation rate"))
+                .on_hover_text("How many simulation steps to take per second. Boids are drawn in between steps, so this doesn't need to match the frame rate.");
+            #[cfg(not(target_arch = "wasm32"))]
+            ui.horizontal(|ui| {
+                ui.checkbox(&mut params.vsync, "Vsync")
+                    .on_hover_text("Show frames in step with the display, which avoids tearing and caps the frame rate at its refresh rate.");
+                ui.add(
+                    egui::Slider::new(&mut params.max_frame_rate, 0.0..=240.0)
+                        .text("Frame rate cap")
+                        .custom_formatter(|value, _| {
+                            if value == 0.0 {
+                                "None".to_string()
+                            } else {
+                                format!("{:.0}", value)
+                            }
+                        }),
+                )
+                .on_hover_text("How many frames to show per second at most, to save battery. Turn off vsync and set this to none to see how fast it can go.");
+            });
+            ui.add(egui::Checkbox::new(&mut params.background_flocking, "Background flocking"))
+                .on_hover_text("Compute the flocking on a background thread so it doesn't stall rendering. The boids are drawn up to a tick behind, and steering them takes up to two ticks.");
+            ui.add(egui::Checkbox::new(&mut params.parallel_flocking, "Parallel flocking"))
+            .on_hover_text("Spread the flocking calculations across all cores.");
+            ui.add(egui::Checkbox::new(&mut params.use_kdtree, "Use k-d tree"))
+                .on_hover_text("Find neighbouring boids with a k-d tree instead of a grid.");
             ui.separator();
-            let max_speed = params.max_speed;
+            let max_speed = params.species[species].max_speed;
             ui.add(
-                egui::Slider::new(&mut params.min_speed, 10.0..=max_speed).text("Minimum speed"),
+                egui::Slider::new(&mut params.species[species].min_speed, 10.0..=max_speed)
+                    .text("Minimum speed"),
             );
-            let min_speed = params.min_speed;
+            let min_speed = params.species[species].min_speed;
             ui.add(
-                egui::Slider::new(&mut params.max_speed, min_speed..=500.0).text("Maximum speed"),
+                egui::Slider::new(&mut params.species[species].max_speed, min_speed..=500.0)
+                    .text("Maximum speed"),
             );
-            ui.add(egui::Checkbox::new(
-                &mut params.bounce_off_walls,
-                "Bounce off walls",
-            ));
+            egui::ComboBox::from_label("Walls")
+                .selected_text(format!("{:?}", params.boundary_mode))
+                .show_ui(ui, |ui| {
+                    ui.selectable_value(&mut params.boundary_mode, BoundaryMode::Bounce, "Bounce");
+                    ui.selectable_value(&mut params.boundary_mode, BoundaryMode::Wrap, "Wrap");
+                    ui.selectable_value(&mut params.boundary_mode, BoundaryMode::Mirror, "Mirror");
+                    ui.selectable_value(&mut params.boundary_mode, BoundaryMode::SoftAvoid, "Soft avoid");
+                    ui.selectable_value(&mut params.boundary_mode, BoundaryMode::Sticky, "Sticky");
+                })
+                .response
+                .on_hover_text("What happens to boids that reach the edge of the world.");
+            ui.horizontal(|ui| {
+                let width = ui
+                    .add(
+                        egui::DragValue::new(&mut params.world_width)
+                            .prefix("World: ")
+                            .clamp_range(100.0..=8192.0),
+                    )
+                    .changed();
+                let height = ui
+                    .add(
+                        egui::DragValue::new(&mut params.world_height)
+                            .prefix("× ")
+                            .clamp_range(100.0..=8192.0),
+                    )
+                    .changed();
+                if width || height {
+                    params.fit_world_to_window = false;
+                }
+                if ui
+                    .add_enabled(
+                        !params.fit_world_to_window,
+                        egui::Button::new("Fit world to window"),
+                    )
+                    .on_hover_text("Make the world as large as the window again, and follow it when it's resized.")
+                    .clicked()
+                {
+                    params.fit_world_to_window = true;
+                }
+            })
+            .response
+            .on_hover_text("How large the area the boids fly in is. Setting it keeps it that size whatever the window, and the camera zooms to show all of it.");
+            if params.boundary_mode == BoundaryMode::SoftAvoid {
+                ui.add(egui::Slider::new(&mut params.wall_margin, 0.0..=200.0).text("Wall margin"))
+                    .on_hover_text("How close to the edge boids start turning away.");
+                ui.add(egui::Slider::new(&mut params.wall_turn_force, 0.0..=50.0).text("Wall turn force"))
+                .on_hover_text("How strongly boids turn away from the edge.");
+            }
+            if params.boundary_mode == BoundaryMode::Sticky {
+                let max_perch_time = params.max_perch_time;
+                ui.add(
+                    egui::Slider::new(&mut params.min_perch_time, 0.0..=max_perch_time)
+                        .text("Shortest rest"),
+                );
+                let min_perch_time = params.min_perch_time;
+                ui.add(
+                    egui::Slider::new(&mut params.max_perch_time, min_perch_time..=30.0)
+                        .text("Longest rest"),
+                )
+                .on_hover_text(
+                    "Boids rest on the edge for a random time in between these, in seconds.",
+                );
+            }
+            ui.add(egui::Checkbox::new(&mut params.lock_centroid, "Follow the flock"))
+                .on_hover_text("Keep the center of the flock in the middle of the window by moving all the boids together, so it can't drift away. The walls stay put, so zoom out or make the window larger to keep them out of the way.");
+            ui.separator();
+            ui.add(egui::Slider::new(&mut params.trail_length, 0..=100).text("Trail length"))
+                .on_hover_text("How many recent positions to draw behind each boid.");
+            ui.add(egui::Checkbox::new(&mut params.trail_mesh, "Fast trails"))
+                .on_hover_text("Draw all the trails at once, which is much faster with many boids. Turn off to draw them line by line instead.");
+            ui.add(egui::Checkbox::new(&mut params.exposure, "Long exposure"))
+                .on_hover_text("Let the boids paint their paths onto the background, like light trails in a photograph, to see how the flock flows over time.");
+            ui.add_enabled(
+                params.exposure,
+                egui::Slider::new(&mut params.exposure_fade, 0.0..=1.0).text("Exposure fade"),
+            )
+            .on_hover_text("How much of their brightness the paths lose every second, from 0 to keep them for good to 1 to only show the latest.");
+            ui.add(egui::Checkbox::new(&mut params.recolor_by_heading, "Recolor boids"))
+                .on_hover_text("Color the boids by what's chosen below as they fly. Otherwise they keep the color they were spawned with.");
+            egui::ComboBox::from_label("Color by")
+                .selected_text(format!("{:?}", params.color_mode))
+                .show_ui(ui, |ui| {
+                    ui.selectable_value(&mut params.color_mode, ColorMode::Heading, "Heading");
+                    ui.selectable_value(&mut params.color_mode, ColorMode::Speed, "Speed");
+                    ui.selectable_value(&mut params.color_mode, ColorMode::Neighbours, "Neighbours");
+                })
+                .response
+                .on_hover_text("Gradients go from blue for the lowest values to red for the highest.");
+            egui::ComboBox::from_label("Shape")
+                .selected_text(format!("{:?}", params.boid_shape))
+                .show_ui(ui, |ui| {
+                    ui.selectable_value(&mut params.boid_shape, BoidShape::Triangle, "Triangle");
+                    ui.selectable_value(&mut params.boid_shape, BoidShape::Arrow, "Arrow");
+                    ui.selectable_value(&mut params.boid_shape, BoidShape::Circle, "Circle");
+                })
+                .response
+                .on_hover_text("What the boids look like. Takes effect on respawn.");
+            ui.horizontal(|ui| {
+                let [r, g, b, _] = params.background.as_rgba_f32();
+                let mut rgb = [r, g, b];
+                if ui.color_edit_button_rgb(&mut rgb).changed() {
+                    params.background = Color::rgb(rgb[0], rgb[1], rgb[2]);
+                }
+                ui.label("Background");
+            });
+            ui.add(egui::Slider::new(&mut params.boid_saturation, 0.0..=1.0).text("Saturation"))
+                .on_hover_text("How colorful the boids are.");
+            ui.add(egui::Slider::new(&mut params.boid_lightness, 0.0..=1.0).text("Lightness"))
+                .on_hover_text("How bright the boids are, from black to white.");
+            ui.horizontal(|ui| {
+                ui.label("Palette");
+                ui.add(
+                    egui::TextEdit::singleline(&mut params.palette)
+                        .hint_text("#1b9e77, #d95f02, #7570b3")
+                        .desired_width(160.0),
+                )
+                .on_hover_text("Hex colors to give the boids in turn instead of the rainbow, unless there are several species. Boids keep these colors unless recolored. Takes effect on respawn.");
+                #[cfg(not(target_arch = "wasm32"))]
+                if ui.button("Load").clicked() {
+                    if let Some(path) = presets::file_dialog("Palette", "txt").pick_file() {
+                        match std::fs::read_to_string(&path) {
+                            Ok(palette) => params.palette = palette.trim().to_string(),
+                            Err(e) => error!("Failed to load palette from {}: {}", path.display(), e),
+                        }
+                    }
+                }
+            });
+            if let Err(e) = parse_palette(&params.palette) {
+                ui.colored_label(egui::Color32::RED, format!("Using the rainbow, {}", e));
+            }
+            ui.add(egui::Checkbox::new(&mut minimap.enabled, "Show minimap"))
+                .on_hover_text("An overview of all the boids and of the part of the window in view.");
+            ui.separator();
+            ui.collapsing("Energy", |ui| {
+                ui.add(egui::Slider::new(&mut params.energy_drain, 0.0..=1.0).text("Drain"))
+                    .on_hover_text("How much energy boids and predators use per second at full speed. Tired ones slow down, 0 means they never tire.");
+                ui.add(egui::Slider::new(&mut params.energy_regen, 0.0..=1.0).text("Recovery"))
+                    .on_hover_text("How much energy they regain per second when stopped, less the faster they go.");
+            });
+            ui.add(egui::Slider::new(&mut params.max_age, 0.0..=120.0).text("Lifespan"))
+                .on_hover_text("How many seconds boids live before new ones replace them, anywhere. They grow paler as they age. 0 means they live forever.");
+            ui.add(egui::Checkbox::new(&mut params.respawn_at_edges, "Respawn at edges"))
+                .on_hover_text("New boids come in from the edges instead of appearing anywhere.");
+            ui.collapsing("Wind", |ui| {
+                ui.add(egui::Slider::new(&mut params.wind.x, -500.0..=500.0).text("Wind x"));
+                ui.add(egui::Slider::new(&mut params.wind.y, -500.0..=500.0).text("Wind y"));
+                ui.add(egui::Checkbox::new(&mut params.wind_gusts, "Gusts"))
+                    .on_hover_text("Slowly change the direction and strength of the wind.");
+            })
+            .header_response
+            .on_hover_text("Lighter boids are pushed around more by the wind.");
+            ui.collapsing("Mouse", |ui| {
+                ui.add(egui::Slider::new(&mut params.mouse_radius, 10.0..=1000.0).text("Radius"))
+                    .on_hover_text(
+                        "How far from the pointer boids feel clicks, more strongly closer to it.",
+                    );
+                ui.add(
+                    egui::Slider::new(&mut params.mouse_attract_force, 0.0..=100.0)
+                        .logarithmic(true)
+                        .text("Attract force"),
+                )
+                .on_hover_text("How strongly left click draws boids in.");
+                ui.add(
+                    egui::Slider::new(&mut params.mouse_repel_force, 0.0..=100.0)
+                        .logarithmic(true)
+                        .text("Repel force"),
+                )
+                .on_hover_text("How strongly right click pushes boids away.");
+                ui.add(
+                    egui::Slider::new(&mut params.follow_force, 0.0..=10.0)
+                        .logarithmic(true)
+                        .text("Follow force"),
+                )
+                .on_hover_text("How strongly boids are drawn to the pointer with the Follow tool. Keep it low so they still flock.");
+            });
+            ui.collapsing("Startle", |ui| {
+                ui.add(egui::Slider::new(&mut params.explode_radius, 10.0..=1000.0).text("Radius"))
+                    .on_hover_text("How far from the center boids get startled.");
+                ui.add(egui::Slider::new(&mut params.explode_strength, 0.0..=2000.0).text("Strength"))
+                    .on_hover_text("How hard startled boids are pushed away, up to their maximum speed.");
+            })
+            .header_response
+            .on_hover_text("Press E to startle the flock from its center, or double click to startle the boids around the pointer.");
+            ui.collapsing("Gravity", |ui| {
+                ui.add(egui::Checkbox::new(&mut params.gravity_enabled, "Enabled"))
+                    .on_hover_text("Ctrl+drag to move the center of gravity.");
+                ui.add(
+                    egui::Slider::new(&mut params.gravity_strength, 0.0..=1000.0)
+                        .logarithmic(true)
+                        .text("Strength"),
+                );
+                ui.add(egui::Checkbox::new(&mut params.gravity_inverse_square, "Inverse square"))
+                    .on_hover_text("Pull more strongly closer to the center, like planets around a star.");
+            })
+            .header_response
+            .on_hover_text("Pull all boids towards a point. Without flocking forces, they orbit or spiral into it.");
+            ui.collapsing("Attractors", |ui| {
+                ui.add(egui::Slider::new(&mut params.attractor_radius, 10.0..=1000.0).text("Radius"))
+                    .on_hover_text("How far attractor and repeller points reach.");
+                ui.add(
+                    egui::Slider::new(&mut params.attractor_strength, 0.0..=100.0)
+                        .logarithmic(true)
+                        .text("Strength"),
+                )
+                .on_hover_text("How strongly new points draw boids in or push them away.");
+                if ui.button("Clear attractors").clicked() {
+                    for e in &attractors {
+                        commands.entity(e).despawn();
+                    }
+                }
+            })
+            .header_response
+            .on_hover_text("Points placed with the Attractors tool that draw boids in or push them away, to sculpt how the flock flows. A line of repellers makes a wall.");
+            ui.collapsing("Obstacles", |ui| {
+                ui.horizontal(|ui| {
+                    ui.selectable_value(&mut params.obstacle_shape, ObstacleShape::Circle, "Circle");
+                    ui.selectable_value(&mut params.obstacle_shape, ObstacleShape::Rectangle, "Rectangle");
+                })
+                .response
+                .on_hover_text("The shape of new obstacles. Shift+click to place an obstacle.");
+                match params.obstacle_shape {
+                    ObstacleShape::Circle => {
+                        ui.add(egui::Slider::new(&mut params.obstacle_radius, 5.0..=200.0).text("Obstacle size"))
+                        .on_hover_text("The radius of new obstacles.");
+                    }
+                    ObstacleShape::Rectangle => {
+                        ui.add(egui::Slider::new(&mut params.obstacle_width, 5.0..=1000.0).text("Obstacle width"));
+                        ui.add(egui::Slider::new(&mut params.obstacle_height, 5.0..=1000.0).text("Obstacle height"));
+                    }
+                }
+                ui.add(
+                    egui::Slider::new(&mut params.obstacle_force, 0.0..=100.0)
+                        .logarithmic(true)
+                        .text("Avoidance force"),
+                )
+                .on_hover_text("How strongly to steer around obstacles.");
+                ui.add(egui::Slider::new(&mut params.lookahead_time, 0.0..=3.0).text("Look ahead"))
+                    .on_hover_text("How many seconds ahead boids look for obstacles in their way, to turn aside in time. 0 means they only avoid the obstacles they're close to.");
+                ui.add(egui::Slider::new(&mut params.waypoint_force, 0.0..=20.0).text("Waypoint force"))
+                .on_hover_text("How strongly to head towards the current waypoint of the scenario.");
+                if ui.button("Clear obstacles").clicked() {
+                    for e in &obstacles {
+                        commands.entity(e).despawn();
+                    }
+                }
+                #[cfg(not(target_arch = "wasm32"))]
+                ui.horizontal(|ui| {
+                    if ui
+                        .button("Save layout")
+                        .on_hover_text("Save the obstacles and predators to a file.")
+                        .clicked()
+                    {
+                        scene_requests.send(SceneRequest::Save);
+                    }
+                    if ui
+                        .button("Load layout")
+                        .on_hover_text("Replace the obstacles and predators with those saved in a file.")
+                        .clicked()
+                    {
+                        scene_requests.send(SceneRequest::Load);
+                    }
+                });
+            });
+            ui.collapsing("Leaders", |ui| {
+                ui.add(
+                    egui::Slider::new(&mut params.number_of_leaders, 0..=8).text("Number of leaders"),
+                )
+                .on_hover_text(
+                    "Boids that follow their own path instead of flocking, circled in white.",
+                );
+                egui::ComboBox::from_label("Path")
+                    .selected_text(format!("{:?}", params.leader_path))
+                    .show_ui(ui, |ui| {
+                        ui.selectable_value(&mut params.leader_path, LeaderPath::Wave, "Wave");
+                        ui.selectable_value(&mut params.leader_path, LeaderPath::Mouse, "Mouse");
+                    })
+                    .response
+                    .on_hover_text(
+                        "Whether leaders weave from side to side, or head towards the mouse pointer.",
+                    );
+                ui.add(
+                    egui::Slider::new(&mut params.leader_weight, 1.0..=100.0)
+                        .logarithmic(true)
+                        .text("Leader weight"),
+                )
+                .on_hover_text("How many times more than other boids leaders count towards cohesion.");
+            });
+            ui.collapsing("Predators", |ui| {
+                ui.add(egui::Slider::new(&mut params.number_of_predators, 0..=16).text("Number of predators"));
+                ui.add(
+                    egui::Slider::new(&mut params.predator_fear_force, 0.0..=100.0)
+                        .logarithmic(true)
+                        .text("Fear force"),
+                )
+                .on_hover_text("How strongly boids flee from predators they can see.");
+                ui.add(egui::Slider::new(&mut params.predator_speed, 10.0..=500.0).text("Predator speed"));
+            });
+            ui.collapsing("History", |ui| {
+                ui.add(egui::Slider::new(&mut params.history_length, 0..=3600).text("Frames"))
+                    .on_hover_text("How many frames to keep for rewinding, 0 to keep none.");
+                ui.add(egui::Slider::new(&mut params.history_interval, 1..=10).text("Interval"))
+                    .on_hover_text("How many simulation steps apart frames are kept. Higher values cover more time with the same memory.");
+                ui.label(format!(
+                    "{:.1} s kept, {:.1} MB",
+                    history.span(params),
+                    history.size() as f32 / 1_000_000.0
+                ));
+                if !history.is_empty() {
+                    let mut shown = history.shown();
+                    let timeline = ui
+                        .add_enabled(
+                            state.paused,
+                            egui::Slider::new(&mut shown, 0..=history.len() - 1).text("Timeline"),
+                        )
+                        .on_hover_text("Rewind to an earlier frame while paused, then step or resume from there.")
+                        .on_disabled_hover_text("Pause to rewind.");
+                    if timeline.changed() {
+                        history.show(shown);
+                    }
+                }
+            });
+            ui.collapsing("Order", |ui| {
+                ui.add(
+                    egui::ProgressBar::new(order.polarization)
+                        .text(format!("Polarization: {:.2}", order.polarization)),
+                )
+                .on_hover_text("How much the boids fly the same way, from 0 when their headings are all over the place to 1 when they're all the same.");
+                ui.add(
+                    egui::ProgressBar::new(order.milling)
+                        .text(format!("Milling: {:.2}", order.milling)),
+                )
+                .on_hover_text("How much the boids circle around the center of the flock, from 0 when they don't to 1 when they all go around it the same way.");
+            })
+            .header_response
+            .on_hover_text("High polarization means the flock flies in one direction, high milling that it swirls around, and both low that it's disordered.");
+            ui.collapsing("Debug", |ui| {
+                ui.add(egui::Checkbox::new(&mut params.log_diagnostics, "Log diagnostics"))
+                    .on_hover_text("Log the frame rate and how strongly each force steers the flock to the console every second.");
+                ui.add(egui::Checkbox::new(&mut debug.show_velocity, "Show velocity"));
+                ui.add(egui::Checkbox::new(&mut debug.show_grid, "Show grid"))
+                    .on_hover_text("The cells boids are bucketed into to find their neighbours, and how many boids are in each. Not used with the k-d tree.");
+                ui.add(egui::Checkbox::new(&mut debug.show_view_radius, "Show view radius"))
+                    .on_hover_text("Of the boid closest to the mouse pointer.");
+                ui.add(egui::Checkbox::new(&mut debug.show_neighbour_links, "Show neighbours"))
+                    .on_hover_text("Of the boid closest to the mouse pointer.");
+                ui.horizontal(|ui| {
+                    ui.add(egui::Checkbox::new(&mut debug.show_hull, "Show outline"))
+                        .on_hover_text("Around the whole flock. Its area grows when separation wins over cohesion, and shrinks the other way around.");
+                    ui.selectable_value(&mut debug.hull_shape, HullShape::Convex, "Hull");
+                    ui.selectable_value(&mut debug.hull_shape, HullShape::BoundingBox, "Box");
+                });
+                if debug.show_hull {
+                    ui.label(format!("Area: {:.0}", debug.hull_area));
+                }
+            });
+            ui.collapsing("Forces", |ui| {
+                Plot::new("forces")
+                    .height(120.0)
+                    .legend(Legend::default())
+                    .show(ui, |plot_ui| {
+                        for (path, name) in &FORCE_DIAGNOSTICS {
+                            let Some(diagnostic) = diagnostics.get(path) else {
+                                continue;
+                            };
+                            let values: Vec<f64> = diagnostic.values().copied().collect();
+                            let points: PlotPoints = (0..values.len())
+                                .map(|i| {
+                                    let window = &values[i.saturating_sub(FORCE_SMOOTHING - 1)..=i];
+                                    [i as f64, window.iter().sum::<f64>() / window.len() as f64]
+                                })
+                                .collect();
+                            plot_ui.line(Line::new(points).name(*name));
+                        }
+                    });
+            })
+            .header_response
+            .on_hover_text("How strongly each force steers the whole flock, over the last few seconds.");
+            ui.collapsing("Heatmap", |ui| {
+                ui.add(egui::Checkbox::new(&mut heatmap.enabled, "Enabled"));
+                ui.add(egui::Slider::new(&mut heatmap.cell_size, 10.0..=200.0).text("Cell size"));
+            })
+            .header_response
+            .on_hover_text("Show how crowded each part of the window is, from blue for empty to red for the densest.");
+            ui.collapsing("Spotlight", |ui| {
+                ui.add(egui::Checkbox::new(&mut spotlight.enabled, "Enabled"))
+                    .on_hover_text("Dim the boids outside of a region to draw attention to it.");
+                egui::ComboBox::from_label("Shape")
+                    .selected_text(format!("{:?}", spotlight.shape))
+                    .show_ui(ui, |ui| {
+                        ui.selectable_value(&mut spotlight.shape, SpotlightShape::Circle, "Circle");
+                        ui.selectable_value(&mut spotlight.shape, SpotlightShape::Rectangle, "Rectangle");
+                    });
+                ui.add(egui::Slider::new(&mut spotlight.size, 10.0..=1000.0).logarithmic(true).text("Size"));
+                ui.add(egui::Slider::new(&mut spotlight.dimming, 0.0..=1.0).text("Dimming"))
+                    .on_hover_text("How bright the boids outside of the spotlight are.");
+                ui.add(egui::Checkbox::new(&mut spotlight.follow_cursor, "Follow cursor"));
+            });
+            ui.collapsing("Help", |ui| {
+                egui::Grid::new("shortcuts").show(ui, |ui| {
+                    for (keys, action) in SHORTCUTS {
+                        ui.label(keys);
+                        ui.label(action);
+                        ui.end_row();
+                    }
+                });
+                ui.label(format!("Presets: {}.", BUILTIN_PRESETS.join(", ")));
+            });
             ui.separator();
-            if ui.button("Restart").clicked() {
-                for mut t in &mut boids {
-                    t.translation.x = thread_rng()
-                        .gen_range(params.window_x_range());
-                    t.translation.y = thread_rng()
-                        .gen_range(params.window_y_range());
+            ui.horizontal(|ui| {
+                ui.label("Mouse:");
+                ui.selectable_value(&mut *tool, ToolMode::Flock, "Flock")
+                    .on_hover_text("Left click attracts the boids, right click repels them.");
+                ui.selectable_value(&mut *tool, ToolMode::Spawn, "Spawn")
+                    .on_hover_text("Left click adds a boid, right click removes the closest one.");
+                ui.selectable_value(&mut *tool, ToolMode::Follow, "Follow")
+                    .on_hover_text("The boids gently follow the pointer, without clicking.");
+                ui.selectable_value(&mut *tool, ToolMode::Select, "Select")
+                    .on_hover_text("Left click shows the stats of the closest boid.");
+                ui.selectable_value(&mut *tool, ToolMode::Attractors, "Attractors")
+                    .on_hover_text("Left click places a point that draws boids in, right click one that pushes them away. Click on one to remove it.");
+                ui.selectable_value(&mut *tool, ToolMode::Obstacles, "Obstacles")
+                    .on_hover_text("Drag obstacles to move them, and scroll over them to resize them.");
+            });
+            ui.horizontal(|ui| {
+                let label = if state.paused { "Resume" } else { "Pause" };
+                if ui.button(label).on_hover_text("Space").clicked() {
+                    state.paused = !state.paused;
+                }
+                if ui.button("Step").on_hover_text("Right arrow").clicked() {
+                    state.paused = true;
+                    state.step_once = true;
+                }
+            });
+            if ui.button("Restart").on_hover_text("R").clicked() {
+                scatter_boids(params, &mut rng.0, &mut scatter, &boids);
+            }
+            ui.horizontal(|ui| {
+                if ui
+                    .button("Randomize")
+                    .on_hover_text("Pick random forces, biases and speeds. Hold Shift to also pick a random number of boids.")
+                    .clicked()
+                {
+                    *previous_params = Some(params.clone());
+                    let number_of_boids = ui.input(|i| i.modifiers.shift);
+                    params.randomize(&mut rng.0, number_of_boids);
+                }
+                if ui
+                    .add_enabled(previous_params.is_some(), egui::Button::new("Undo"))
+                    .on_hover_text("Go back to the parameters from before randomizing or resetting.")
+                    .clicked()
+                {
+                    if let Some(mut previous) = previous_params.take() {
+                        previous.keep_fitted_world(params);
+                        *params = previous;
+                    }
                 }
+            });
+            ui.horizontal(|ui| {
+                ui.add(egui::DragValue::new(&mut params.seed).prefix("Seed: ").clamp_range(0..=u32::MAX))
+                .on_hover_text("Runs with the same seed and parameters are identical.");
+                if ui.button("Reseed").clicked() {
+                    params.seed = random_seed();
+                    respawn.requested = true;
+                }
+                if ui.button("Respawn").clicked() {
+                    respawn.requested = true;
+                }
+            });
+            egui::ComboBox::from_label("Spawn pattern")
+                .selected_text(format!("{:?}", params.spawn_pattern))
+                .show_ui(ui, |ui| {
+                    ui.selectable_value(&mut params.spawn_pattern, SpawnPattern::Uniform, "Uniform");
+                    ui.selectable_value(&mut params.spawn_pattern, SpawnPattern::Cluster, "Cluster");
+                    ui.selectable_value(&mut params.spawn_pattern, SpawnPattern::Ring, "Ring");
+                    ui.selectable_value(&mut params.spawn_pattern, SpawnPattern::Grid, "Grid");
+                })
+                .response
+                .on_hover_text("Where boids start from: anywhere, in a blob in the middle, going around a circle, or standing still on a lattice. Takes effect on respawn.");
+            ui.add(egui::Slider::new(&mut params.initial_alignment, 0.0..=1.0).text("Initial alignment"))
+                .on_hover_text("How much boids start off going the same way, from 0 for each its own way to 1 for the whole flock in one direction. Doesn't apply to the ring and grid patterns, which have their own headings. Takes effect on respawn.");
+            #[cfg(not(target_arch = "wasm32"))]
+            ui.horizontal(|ui| {
+                if ui.button("Save preset").clicked() {
+                    presets::save_with_dialog(params);
+                }
+                if ui.button("Load preset").clicked() {
+                    if let Some(mut preset) = presets::load_with_dialog() {
+                        preset.keep_fitted_world(params);
+                        *params = preset;
+                    }
+                }
+            });
+            if ui
+                .button("Reset to defaults")
+                .on_hover_text("Go back to the default parameters. The parameters are otherwise kept between runs.")
+                .clicked()
+            {
+                *previous_params = Some(params.clone());
+                // The world keeps fitting the window, and the flock its seed.
+                *params = Parameters {
+                    world_width: params.world_width,
+                    world_height: params.world_height,
+                    seed: params.seed,
+                    ..default()
+                };
             }
+            #[cfg(not(target_arch = "wasm32"))]
+            ui.horizontal(|ui| {
+                if ui
+                    .button("Capture")
+                    .on_hover_text("Save a screenshot of the window to the working directory.")
+                    .clicked()
+                {
+                    capture.requested = true;
+                }
+                ui.checkbox(&mut capture.hide_ui, "Hide parameters");
+                ui.checkbox(&mut capture.caption, "Include parameter caption")
+                    .on_hover_text("Show the number of boids, the forces and the seed along the bottom of the screenshot.");
+            });
         });
+    if *params != before {
+        params.set_changed();
+    }
+}
+
+// How wide the parameters panel is when docked, in logical pixels.
+const PANEL_WIDTH: f32 = 320.0;
+
+// Where the parameters are shown: in a panel beside the boids, or in a window over them.
+#[derive(Default)]
+struct ParametersContainer {
+    docked: bool,
+}
+
+impl ParametersContainer {
+    fn docked(mut self, docked: bool) -> Self {
+        self.docked = docked;
+        self
+    }
+
+    fn show(self, ctx: &egui::Context, add_contents: impl FnOnce(&mut egui::Ui)) {
+        if self.docked {
+            // Takes space from the simulation instead of covering it, see fit_to_panel().
+            egui::SidePanel::left("parameters")
+                .exact_width(PANEL_WIDTH)
+                .show(ctx, |ui| {
+                    egui::ScrollArea::vertical().show(ui, add_contents)
+                });
+        } else {
+            egui::Window::new("Parameters")
+                .default_open(false)
+                .show(ctx, add_contents);
+        }
+    }
 }
 
-fn window_resize(
-    mut resize_reader: EventReader<WindowResized>,
+// Keeps the simulation to the part of the window the docked parameters panel leaves. The world
+// takes the size of that part when fitting it to the window, otherwise the camera zooms to show
+// all of the world in it. The boids are constrained to the world whenever it changes size.
+fn fit_to_panel(
+    mut contexts: EguiContexts,
+    capture: Res<Capture>,
+    window: Query<&Window, With<PrimaryWindow>>,
+    mut camera: Query<(&mut Camera, &mut OrthographicProjection)>,
     mut params: ResMut<Parameters>,
     mut query: Query<&mut Transform, With<Boid>>,
+    mut world_size: Local<Vec2>,
 ) {
-    if let Some(e) = resize_reader.read().last() {
-        if params.window_width == e.width && params.window_height == e.height {
-            return;
-        }
-        params.window_width = e.width;
-        params.window_height = e.height;
+    // The panel is only hidden for a moment while taking a screenshot.
+    if capture.hiding {
+        return;
+    }
+    let Ok(window) = window.get_single() else {
+        return;
+    };
+    let rect = contexts.ctx_mut().available_rect();
+    let window_size = Vec2::new(window.width(), window.height());
+    let size = Vec2::new(rect.width(), rect.height()).min(window_size);
+    if size.x < 1.0 || size.y < 1.0 {
+        return; // Minimized.
+    }
 
-        for mut t in &mut query {
-            // Constrain the boids to the new window size.
-            t.translation = t
-                .translation
-                .clamp(params.min_position(), params.max_position());
-        }
+    let scale = window.scale_factor();
+    let viewport = (size != window_size).then(|| Viewport {
+        physical_position: (Vec2::new(rect.min.x, rect.min.y).max(Vec2::ZERO) * scale).as_uvec2(),
+        physical_size: (size * scale).as_uvec2(),
+        ..default()
+    });
+    let (mut camera, mut projection) = camera.single_mut();
+    let area = |viewport: &Viewport| (viewport.physical_position, viewport.physical_size);
+    if camera.viewport.as_ref().map(area) != viewport.as_ref().map(area) {
+        camera.viewport = viewport;
+    }
+
+    if params.fit_world_to_window && params.world_size() != size {
+        params.world_width = size.x;
+        params.world_height = size.y;
+    }
+    // Only touch the projection when it changes, it's recomputed whenever it's touched.
+    let fitted = match projection.scaling_mode {
+        ScalingMode::WindowSize(_) => params.fit_world_to_window,
+        ScalingMode::AutoMin {
+            min_width,
+            min_height,
+        } => !params.fit_world_to_window && Vec2::new(min_width, min_height) == params.world_size(),
+        _ => false,
+    };
+    if !fitted {
+        projection.scaling_mode = if params.fit_world_to_window {
+            ScalingMode::WindowSize(1.0)
+        } else {
+            ScalingMode::AutoMin {
+                min_width: params.world_width,
+                min_height: params.world_height,
+            }
+        };
+    }
+
+    if *world_size == params.world_size() {
+        return;
+    }
+    *world_size = params.world_size();
+    for mut t in &mut query {
+        // Constrain the boids to the new size.
+        t.translation = t
+            .translation
+            .clamp(params.min_position(), params.max_position());
     }
 }
 
+// Adds the simulation, which takes fixed steps so it behaves the same whatever the frame rate.
+// Everything that changes the boids or draws from the RNG is part of a step, including adding and
+// removing boids, so a run can be replayed exactly from its seed and parameters, see replay.rs.
+// The parameters and the RNG are left to the caller.
+fn add_simulation(app: &mut App) -> &mut App {
+    register_force_diagnostics(app);
+    app.init_resource::<Waypoints>()
+        .init_resource::<Respawn>()
+        .init_resource::<SimState>()
+        .init_resource::<SpawnCounter>()
+        .init_resource::<ScatterState>()
+        .init_resource::<History>()
+        .init_resource::<DebugDraw>()
+        .init_resource::<BackgroundFlock>()
+        .init_resource::<SpatialGrid>()
+        .init_resource::<KdTree>()
+        .init_resource::<Centroid>()
+        .init_resource::<BoidMesh>()
+        .init_resource::<ToolMode>()
+        .add_systems(
+            FixedUpdate,
+            (
+                restore_transforms,
+                (
+                    (
+                        respawn,
+                        adjust_number_of_boids,
+                        adjust_number_of_species,
+                        (adjust_number_of_predators, adjust_number_of_leaders),
+                        fade,
+                    )
+                        .chain(),
+                    hunt,
+                    build_grid,
+                    build_kdtree,
+                    find_centroid,
+                    flock,
+                    (steer_boids, lead).chain(),
+                    flock_in_background,
+                    blow,
+                    (gravitate, attract).chain(),
+                    follow_waypoints,
+                    handle_mouse,
+                    avoid_obstacles,
+                    flee_predators,
+                    avoid_walls,
+                    handle_walls,
+                    (perch, fly, lock_centroid, tire, grow_old).chain(),
+                    collide_with_obstacles,
+                    (keep_apart, fix_non_finite).chain(),
+                    history::record,
+                )
+                    .chain()
+                    .run_if(is_simulating),
+                save_transforms,
+                finish_step,
+            )
+                .chain(),
+        )
+}
+
 fn main() {
+    // The size of the window, and of the world while it fits the window, until it's resized.
+    #[cfg_attr(target_arch = "wasm32", allow(unused_mut))]
+    let mut window_size = Vec2::new(640.0, 480.0);
+    #[cfg_attr(target_arch = "wasm32", allow(unused_mut))]
+    let mut params = Parameters {
+        world_width: window_size.x,
+        world_height: window_size.y,
+        ..default()
+    };
+    // There are no command line flags on the web.
+    #[cfg_attr(target_arch = "wasm32", allow(unused_mut))]
+    let mut scenario: Option<Scenario> = None;
+
+    #[cfg(not(target_arch = "wasm32"))]
+    let (mut recorder, mut metrics) = (None, None);
+    #[cfg(not(target_arch = "wasm32"))]
+    let sprites;
+    #[cfg(not(target_arch = "wasm32"))]
+    let (mut replay_recorder, mut replay_player) = (None, None);
+    #[cfg(not(target_arch = "wasm32"))]
+    {
+        use clap::Parser;
+
+        let args = cli::Args::parse();
+        // Benchmarks always start from the defaults, so they're comparable.
+        if args.bench.is_none() {
+            match presets::load_saved() {
+                // A new flock every run, unless a seed is given.
+                Ok(Some(saved)) => {
+                    params = Parameters {
+                        seed: params.seed,
+                        ..saved
+                    }
+                }
+                Ok(None) => {}
+                Err(e) => eprintln!(
+                    "Failed to load the saved parameters, using the defaults: {}",
+                    e
+                ),
+            }
+        }
+        if let Some(path) = &args.scenario {
+            match scenario::load(path) {
+                Ok(loaded) => scenario = Some(loaded),
+                Err(e) => {
+                    eprintln!("Failed to load scenario {:?}: {}", path, e);
+                    return;
+                }
+            }
+        }
+        if let Some(path) = &args.record {
+            match record::Recorder::create(path) {
+                Ok(created) => recorder = Some(created),
+                Err(e) => {
+                    eprintln!("Failed to create recording {:?}: {}", path, e);
+                    return;
+                }
+            }
+        }
+        if let Some(path) = &args.metrics {
+            match metrics::MetricsWriter::create(path) {
+                Ok(created) => metrics = Some(created),
+                Err(e) => {
+                    eprintln!("Failed to create metrics file {:?}: {}", path, e);
+                    return;
+                }
+            }
+        }
+        // Flags take precedence over the scenario.
+        if let Some(number_of_boids) = scenario.as_ref().and_then(|s| s.number_of_boids) {
+            params.number_of_boids = number_of_boids;
+        }
+        args.apply(&mut params);
+
+        if let Some(how_many) = args.bench {
+            // Benchmarks default to a larger area, so they're comparable with earlier ones.
+            params.number_of_boids = how_many;
+            params.world_width = args.width.unwrap_or(1280.0);
+            params.world_height = args.height.unwrap_or(720.0);
+            bench::run(params, recorder);
+            return;
+        }
+        if params.fit_world_to_window {
+            // The window opens as large as it was last time.
+            window_size = params.world_size();
+        }
+        window_size.x = args.width.unwrap_or(window_size.x);
+        window_size.y = args.height.unwrap_or(window_size.y);
+        if params.fit_world_to_window {
+            params.world_width = window_size.x;
+            params.world_height = window_size.y;
+        }
+        if let Some(path) = &args.replay {
+            match replay::load(path) {
+                Ok(loaded) => {
+                    params = loaded.parameters.clone();
+                    // Replays play out in a world as large as the one they were recorded in.
+                    if params.fit_world_to_window {
+                        window_size = params.world_size();
+                    }
+                    replay_player = Some(replay::ReplayPlayer::new(loaded));
+                }
+                Err(e) => {
+                    eprintln!("Failed to load replay {:?}: {}", path, e);
+                    return;
+                }
+            }
+        }
+        if let Some(path) = &args.record_replay {
+            replay_recorder = Some(replay::ReplayRecorder::new(path.clone(), params.clone()));
+        }
+        if args.three_d {
+            three_d::run(params);
+            return;
+        }
+        sprites = args.sprites;
+    }
+
     let mut app = App::new();
     app.add_plugins((
         DefaultPlugins.set(WindowPlugin {
             primary_window: Some(Window {
-                resolution: (640., 480.).into(),
+                // The docked parameters panel takes some of the width.
+                resolution: (
+                    window_size.x
+                        + if params.dock_parameters {
+                            PANEL_WIDTH
+                        } else {
+                            0.0
+                        },
+                    window_size.y,
+                )
+                    .into(),
+                present_mode: present_mode(&params),
+                // On the web, draw into the canvas of docs/index.html. Build it with:
+                //
+                //   cargo build --release --target wasm32-unknown-unknown
+                //   wasm-bindgen --no-typescript --target web --out-dir ./docs --out-name boids \
+                //     ./target/wasm32-unknown-unknown/release/boids.wasm
+                //
+                // which is what build_wasm.sh does, then serve the docs directory.
+                #[cfg(target_arch = "wasm32")]
+                canvas: Some("#boids".into()),
                 ..default()
             }),
             ..default()
         }),
         EguiPlugin,
+        FrameTimeDiagnosticsPlugin,
     ))
-    .insert_resource(Parameters::default())
-    .add_systems(Startup, setup)
-    .add_systems(
+    .insert_resource(Time::<Fixed>::from_hz(params.simulation_hz.into()))
+    .insert_resource(RngResource::new(params.seed))
+    .insert_resource(params)
+    .add_event::<Explode>()
+    .add_event::<SceneRequest>()
+    .register_type::<Obstacle>()
+    .register_type::<Predator>()
+    .init_resource::<Capture>()
+    .init_resource::<Order>()
+    .init_resource::<Heatmap>()
+    .init_resource::<Minimap>()
+    .init_resource::<Selected>()
+    .init_resource::<Spotlight>()
+    .add_systems(Startup, (setup, load_scenario));
+    add_simulation(&mut app).add_systems(
+        Update,
+        (
+            (
+                interpolate_transforms,
+                show_background_flock,
+                animate_scatter,
+            )
+                .chain(),
+            (
+                set_timestep,
+                (set_background, set_present_mode, log_diagnostics),
+                (
+                    measure_order,
+                    parameters_ui,
+                    fit_to_panel.before(inspect_selected),
+                )
+                    .chain(),
+                (pause_controls, history::rewind),
+                (keyboard_shortcuts, trigger_explosions, explode).chain(),
+                control_camera,
+                (place_obstacles, edit_obstacles, place_attractors),
+                drag_attractor,
+                use_spawn_tool,
+                complete_loaded_entities,
+                (draw_trails, draw_trail_mesh, expose),
+                (
+                    draw_debug,
+                    draw_grid,
+                    draw_leaders,
+                    draw_hull,
+                    draw_attractors,
+                ),
+                draw_heatmap,
+                draw_waypoints,
+                draw_minimap,
+                (move_spotlight, recolor, show_fade).chain(),
+                (use_select_tool, inspect_selected).chain(),
+            )
+                .after(animate_scatter),
+        ),
+    );
+
+    if let Some(scenario) = scenario {
+        app.insert_resource(scenario);
+    }
+    #[cfg(not(target_arch = "wasm32"))]
+    if sprites {
+        let sprite = app.world.resource_mut::<Assets<Image>>().add(boid_sprite());
+        app.world.resource_mut::<BoidMesh>().sprite = Some(sprite);
+    }
+    #[cfg(not(target_arch = "wasm32"))]
+    app.add_systems(
         Update,
         (
-            parameters_ui,
-            adjust_number_of_boids,
-            (flock, handle_mouse, handle_walls, fly).chain(),
+            scenes::handle_requests,
+            take_screenshot.before(parameters_ui),
+            draw_caption.after(take_screenshot).before(parameters_ui),
         ),
     )
-    .add_systems(PostUpdate, window_resize);
+    .add_systems(Last, (presets::save_on_exit, limit_frame_rate));
+    #[cfg(not(target_arch = "wasm32"))]
+    if let Some(recorder) = recorder {
+        // Record every simulation step.
+        app.insert_resource(recorder).add_systems(
+            FixedUpdate,
+            record::record
+                .after(keep_apart)
+                .before(save_transforms)
+                .run_if(resource_exists::<record::Recorder>)
+                .run_if(is_simulating),
+        );
+    }
+    // Parameter changes take effect from the next simulation step, which is when they're noted
+    // and replayed.
+    #[cfg(not(target_arch = "wasm32"))]
+    if let Some(recorder) = replay_recorder {
+        app.insert_resource(recorder)
+            .add_systems(
+                FixedUpdate,
+                replay::record.before(respawn).run_if(is_simulating),
+            )
+            .add_systems(Last, replay::save_on_exit);
+    }
+    #[cfg(not(target_arch = "wasm32"))]
+    if let Some(player) = replay_player {
+        app.insert_resource(player).add_systems(
+            FixedUpdate,
+            replay::play.before(respawn).run_if(is_simulating),
+        );
+    }
+    // Once per frame, after the boids have moved and been drawn where they are.
+    #[cfg(not(target_arch = "wasm32"))]
+    if let Some(metrics) = metrics {
+        app.insert_resource(metrics).add_systems(
+            PostUpdate,
+            metrics::write_metrics.run_if(resource_exists::<metrics::MetricsWriter>),
+        );
+    }
+    app.run();
+}
 
-    #[cfg(debug_assertions)]
-    {
-        use bevy::diagnostic::{FrameTimeDiagnosticsPlugin, LogDiagnosticsPlugin};
-        // Log the FPS in debug builds.
-        app.add_plugins((FrameTimeDiagnosticsPlugin, LogDiagnosticsPlugin::default()));
+#[cfg(test)]
+mod tests {
+    use bevy::ecs::system::RunSystemOnce;
+
+    use super::*;
+
+    #[test]
+    fn boids_at_the_same_position_separate() {
+        let params = Parameters {
+            fov_degrees: 360.0,
+            ..default()
+        };
+        let (e1, e2) = (Entity::from_raw(1), Entity::from_raw(2));
+        let (b1, b2) = (Boid::new(0.0, 10.0, 1.0, 0), Boid::new(0.0, 10.0, 1.0, 0));
+        let (mut p1, mut p2) = (Vec2::new(5.0, 5.0), Vec2::new(5.0, 5.0));
+        let mut distance = 0.0;
+        for _ in 0..3 {
+            let (mut c1, mut c2) = (Calculations::default(), Calculations::default());
+            perceive(&params, e1, p1, &b1, &mut c1, e2, p2, &b2, false);
+            perceive(&params, e2, p2, &b2, &mut c2, e1, p1, &b1, false);
+            assert!(c1.separation.is_finite() && c1.separation != Vec2::ZERO);
+            assert!(c2.separation.is_finite() && c2.separation != Vec2::ZERO);
+
+            p1 += flocking::steering(c1.separation, 1.0, params.steering_force) / 60.0;
+            p2 += flocking::steering(c2.separation, 1.0, params.steering_force) / 60.0;
+            assert!(p1.distance(p2) > distance);
+            distance = p1.distance(p2);
+        }
+        // The same way every time, along the direction picked for the pair.
+        let direction = (p1 - p2).normalize();
+        assert!(direction.abs_diff_eq(flocking::overlap_direction(e2, e1), 1e-4));
     }
 
-    app.run();
+    #[test]
+    fn boids_are_clamped_to_a_wide_world() {
+        let params = Parameters {
+            world_width: 1920.0,
+            world_height: 480.0,
+            ..default()
+        };
+        assert_eq!(params.max_position(), Vec3::new(960.0, 240.0, 0.0));
+        assert_eq!(params.min_position(), Vec3::new(-960.0, -240.0, 0.0));
+        let clamp = |p: Vec3| p.clamp(params.min_position(), params.max_position());
+        assert_eq!(
+            clamp(Vec3::new(900.0, 0.0, 0.0)),
+            Vec3::new(900.0, 0.0, 0.0)
+        );
+        assert_eq!(
+            clamp(Vec3::new(1000.0, 300.0, 0.0)),
+            Vec3::new(960.0, 240.0, 0.0)
+        );
+        assert_eq!(
+            clamp(Vec3::new(-1000.0, -300.0, 0.0)),
+            Vec3::new(-960.0, -240.0, 0.0)
+        );
+    }
+
+    // The spawn index and position of each boid, in spawn order.
+    fn boids(app: &mut App) -> Vec<(u64, Vec2)> {
+        let mut boids: Vec<(u64, Vec2)> = app
+            .world
+            .query_filtered::<(&SpawnIndex, &Transform), With<Boid>>()
+            .iter(&app.world)
+            .map(|(i, t)| (i.0, t.translation.truncate()))
+            .collect();
+        boids.sort_by_key(|&(i, _)| i);
+        boids
+    }
+
+    // Spawns 10 boids, lowers their number to 4 and raises it back to 10.
+    #[allow(clippy::type_complexity)]
+    fn lower_then_raise(seed: u64) -> (Vec<(u64, Vec2)>, Vec<(u64, Vec2)>) {
+        let mut app = App::new();
+        app.insert_resource(RngResource::new(seed))
+            .insert_resource(Parameters {
+                number_of_boids: 10,
+                seed,
+                ..default()
+            })
+            .init_resource::<SpawnCounter>()
+            .add_systems(Update, adjust_number_of_boids);
+        app.update();
+        app.world.resource_mut::<Parameters>().number_of_boids = 4;
+        app.update();
+        let lowered = boids(&mut app);
+        app.world.resource_mut::<Parameters>().number_of_boids = 10;
+        app.update();
+        (lowered, boids(&mut app))
+    }
+
+    #[test]
+    fn lowering_then_raising_the_number_of_boids_is_stable() {
+        let (lowered, raised) = lower_then_raise(42);
+        let indices = |boids: &[(u64, Vec2)]| boids.iter().map(|&(i, _)| i).collect::<Vec<_>>();
+        // The most recently spawned boids go first, and new ones get new indices.
+        assert_eq!(indices(&lowered), [0, 1, 2, 3]);
+        assert_eq!(indices(&raised), [0, 1, 2, 3, 10, 11, 12, 13, 14, 15]);
+        assert_eq!(raised[..4], lowered[..]);
+        assert_eq!(lower_then_raise(42), (lowered, raised));
+    }
+
+    // Whether pressing space pauses the simulation, with or without a text field like the palette
+    // being typed into.
+    fn space_pauses(typing: bool) -> bool {
+        let mut app = App::new();
+        app.init_resource::<ButtonInput<KeyCode>>()
+            .init_resource::<SimState>()
+            .init_resource::<bevy_egui::EguiUserTextures>()
+            .add_systems(Update, pause_controls);
+        let mut context = bevy_egui::EguiContext::default();
+        if typing {
+            context
+                .get_mut()
+                .memory_mut(|memory| memory.request_focus(egui::Id::new("palette")));
+        }
+        app.world.spawn((Window::default(), PrimaryWindow, context));
+        app.world
+            .resource_mut::<ButtonInput<KeyCode>>()
+            .press(KeyCode::Space);
+        app.update();
+        app.world.resource::<SimState>().paused
+    }
+
+    #[test]
+    fn typing_a_space_into_the_palette_does_not_pause() {
+        assert!(space_pauses(false));
+        assert!(!space_pauses(true));
+    }
+
+    #[test]
+    fn boids_see_each_other_across_the_seam_when_wrapping() {
+        let (e1, e2) = (Entity::from_raw(1), Entity::from_raw(2));
+        let boids = [
+            (e1, Vec2::new(-99.0, 0.0), Boid::new(0.0, 10.0, 1.0, 0)),
+            (e2, Vec2::new(99.0, 0.0), Boid::new(0.0, 10.0, 1.0, 0)),
+        ];
+        let boid = |e| {
+            boids
+                .iter()
+                .find(|(e2, ..)| *e2 == e)
+                .map(|(_, p, b)| (*p, b, false))
+        };
+        for use_kdtree in [false, true] {
+            let params = Parameters {
+                world_width: 200.0,
+                world_height: 200.0,
+                boundary_mode: BoundaryMode::Wrap,
+                fidelity: 1.0,
+                use_kdtree,
+                ..default()
+            };
+            let mut grid = SpatialGrid {
+                cell_size: params.view_distance,
+                cells: HashMap::new(),
+            };
+            for &(e, p, _) in &boids {
+                let cell = grid.cell(p);
+                grid.cells.entry(cell).or_default().push(e);
+            }
+            let mut tree = KdTree::default();
+            tree.build(boids.iter().map(|&(e, p, _)| (e, p)));
+
+            let (_, p1, b1) = &boids[0];
+            let mut c1 = Calculations::default();
+            perceive_neighbours(&params, &grid, &tree, boid, 0, e1, *p1, b1, &mut c1);
+            assert_eq!(c1.neighbours, 1);
+            // The other boid is seen 2 to the left across the seam, not 198 to the right.
+            assert!(c1.separation.x > 0.0);
+            assert_eq!(c1.cohesion.x / c1.weights, -2.0);
+        }
+    }
+
+    // The cohesion force on a boid at the origin from a neighbour at the given position and
+    // velocity.
+    fn cohesion(params: &Parameters, p2: Vec2, v2: Vec2, leader: bool) -> Vec2 {
+        let (b1, b2) = (Boid::new(0.0, 10.0, 1.0, 0), Boid::new(v2.x, v2.y, 1.0, 0));
+        let mut c1 = Calculations::default();
+        let (e1, e2) = (Entity::from_raw(1), Entity::from_raw(2));
+        perceive(params, e1, Vec2::ZERO, &b1, &mut c1, e2, p2, &b2, leader);
+        forces(params, &c1, &b1)[0]
+    }
+
+    #[test]
+    fn cohesion_leads_neighbours_by_the_prediction_time() {
+        let params = Parameters {
+            prediction_time: 1.0,
+            ..default()
+        };
+        let force = cohesion(&params, Vec2::new(10.0, 0.0), Vec2::new(0.0, 100.0), false);
+        // Towards (10, 100), where the neighbour will be in a second.
+        assert!(force.x > 0.0 && force.y > 0.0);
+        assert!(force.angle_between(Vec2::new(10.0, 100.0)).abs() < 1e-5);
+    }
+
+    #[test]
+    fn followers_are_drawn_to_their_leader() {
+        let params = Parameters {
+            leader_weight: 4.0,
+            weighted_cohesion: true,
+            ..default()
+        };
+        let force = cohesion(&params, Vec2::new(50.0, 0.0), Vec2::new(0.0, 10.0), true);
+        assert!(force.x > 0.0);
+        assert_eq!(force.y, 0.0);
+    }
+
+    #[test]
+    fn boids_avoid_obstacles_across_the_edges() {
+        let mut world = World::new();
+        world.insert_resource(Parameters {
+            world_width: 200.0,
+            world_height: 200.0,
+            boundary_mode: BoundaryMode::Wrap,
+            ..default()
+        });
+        world.spawn((
+            Transform::from_xyz(95.0, 0.0, 0.0),
+            Obstacle::Circle { radius: 5.0 },
+        ));
+        // Flying along the left edge, 10 away from the center of the obstacle on the right one.
+        let boid = world
+            .spawn((
+                Transform::from_xyz(-95.0, 0.0, 0.0),
+                Boid::new(0.0, 10.0, 1.0, 0),
+            ))
+            .id();
+        world.run_system_once(avoid_obstacles);
+        assert!(world.get::<Boid>(boid).unwrap().velocity.x > 0.0);
+    }
+
+    #[test]
+    fn two_boids_yield_the_expected_forces() {
+        let mut params = Parameters {
+            separation_distance: 32.0,
+            separation_curve: SeparationCurve::Linear,
+            alignment_bias: 1.0,
+            steering_force: 100.0,
+            ..default()
+        };
+        params.species[0] = SpeciesParams {
+            cohesion_force: 0.5,
+            separation_force: 2.0,
+            alignment_force: 0.25,
+            ..default()
+        };
+        let (b1, b2) = (Boid::new(0.0, 10.0, 1.0, 0), Boid::new(10.0, 0.0, 1.0, 0));
+        let (e1, e2) = (Entity::from_raw(1), Entity::from_raw(2));
+        let mut c1 = Calculations::default();
+        perceive(
+            &params,
+            e1,
+            Vec2::ZERO,
+            &b1,
+            &mut c1,
+            e2,
+            Vec2::new(16.0, 0.0),
+            &b2,
+            false,
+        );
+        // Cohesion towards the other boid, 16 to the right. Separation away from it, at half of
+        // the linear separation range: 16 * (1 - 16 / 32) / 16. Alignment with its velocity,
+        // whatever the angle between them with no bias.
+        assert_eq!(
+            forces(&params, &c1, &b1),
+            [
+                Vec2::new(8.0, 0.0),
+                Vec2::new(-1.0, 0.0),
+                Vec2::new(2.5, 0.0),
+                Vec2::ZERO,
+            ]
+        );
+    }
 }
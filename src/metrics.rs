@@ -0,0 +1,79 @@
+// Writing statistics about the whole flock to a JSON lines file, one object per frame, for
+// analysis pipelines that don't need every boid's trajectory.
+
+use std::{
+    fs::File,
+    io::{self, BufWriter, Write},
+    path::Path,
+};
+
+use bevy::{app::AppExit, prelude::*};
+
+use crate::{flocking, Boid, Calculations};
+
+// How many frames to buffer before writing them out.
+const FLUSH_INTERVAL: u64 = 60;
+
+#[derive(Resource)]
+pub struct MetricsWriter {
+    writer: BufWriter<File>,
+    frame: u64,
+}
+
+impl MetricsWriter {
+    pub fn create(path: &Path) -> io::Result<Self> {
+        Ok(Self {
+            writer: BufWriter::new(File::create(path)?),
+            frame: 0,
+        })
+    }
+}
+
+// Appends the statistics of the current frame, once the boids have moved.
+pub fn write_metrics(
+    mut commands: Commands,
+    mut metrics: ResMut<MetricsWriter>,
+    mut exit: EventReader<AppExit>,
+    query: Query<(&Transform, &Boid, &Calculations)>,
+) {
+    let count = query.iter().len().max(1) as f32;
+    let mut positions = Vec::new();
+    let (mut speed, mut neighbours) = (0.0, 0.0);
+    for (t, boid, calculations) in &query {
+        positions.push(t.translation.truncate());
+        speed += boid.velocity.length();
+        neighbours += calculations.neighbours as f32;
+    }
+    let average_speed = speed / count;
+    let speed_variance = query
+        .iter()
+        .map(|(_, boid, _)| (boid.velocity.length() - average_speed).powi(2))
+        .sum::<f32>()
+        / count;
+    let centroid = positions.iter().sum::<Vec2>() / count;
+    let polarization = flocking::polarization(query.iter().map(|(_, boid, _)| boid.velocity));
+    let hull_area = flocking::polygon_area(&flocking::convex_hull(positions));
+
+    let frame = metrics.frame;
+    let mut result = writeln!(
+        metrics.writer,
+        "{{\"frame\":{},\"average_speed\":{},\"speed_variance\":{},\"mean_neighbours\":{},\"centroid\":[{},{}],\"hull_area\":{},\"polarization\":{}}}",
+        frame,
+        average_speed,
+        speed_variance,
+        neighbours / count,
+        centroid.x,
+        centroid.y,
+        hull_area,
+        polarization
+    );
+    metrics.frame += 1;
+    let exiting = exit.read().next().is_some();
+    if result.is_ok() && (metrics.frame.is_multiple_of(FLUSH_INTERVAL) || exiting) {
+        result = metrics.writer.flush();
+    }
+    if let Err(e) = result {
+        error!("Stopped writing metrics: {}", e);
+        commands.remove_resource::<MetricsWriter>();
+    }
+}
@@ -0,0 +1,145 @@
+// Saving and loading parameter presets to TOML files, and keeping the parameters between runs.
+
+use std::{
+    error::Error,
+    fs,
+    path::{Path, PathBuf},
+};
+
+use bevy::{
+    app::AppExit,
+    ecs::prelude::*,
+    log::{error, info},
+};
+
+use crate::Parameters;
+
+pub fn save(params: &Parameters, path: &Path) -> Result<(), Box<dyn Error>> {
+    fs::write(path, toml::to_string_pretty(params)?)?;
+    Ok(())
+}
+
+pub fn load(path: &Path) -> Result<Parameters, Box<dyn Error>> {
+    parse(&fs::read_to_string(path)?)
+}
+
+fn parse(preset: &str) -> Result<Parameters, Box<dyn Error>> {
+    let mut params: Parameters = toml::from_str(preset)?;
+    params.fix_species();
+    Ok(params)
+}
+
+// Files are kept next to the executable by default.
+pub fn file_dialog(name: &str, extension: &str) -> rfd::FileDialog {
+    let dialog = rfd::FileDialog::new().add_filter(name, &[extension]);
+    match std::env::current_exe()
+        .ok()
+        .and_then(|exe| exe.parent().map(PathBuf::from))
+    {
+        Some(dir) => dialog.set_directory(dir),
+        None => dialog,
+    }
+}
+
+// Asks where to save the parameters, then saves them there.
+pub fn save_with_dialog(params: &Parameters) {
+    let Some(path) = file_dialog("Preset", "toml")
+        .set_file_name("preset.toml")
+        .save_file()
+    else {
+        return; // Cancelled.
+    };
+    match save(params, &path) {
+        Ok(()) => info!("Saved preset to {}", path.display()),
+        Err(e) => error!("Failed to save preset to {}: {}", path.display(), e),
+    }
+}
+
+// Asks which preset to load, then loads it.
+pub fn load_with_dialog() -> Option<Parameters> {
+    let path = file_dialog("Preset", "toml").pick_file()?;
+    match load(&path) {
+        Ok(params) => {
+            info!("Loaded preset from {}", path.display());
+            Some(params)
+        }
+        Err(e) => {
+            error!("Failed to load preset from {}: {}", path.display(), e);
+            None
+        }
+    }
+}
+
+// Where the parameters are kept between runs, in the platform's config directory.
+fn saved_path() -> Option<PathBuf> {
+    directories::ProjectDirs::from("", "", "boids")
+        .map(|dirs| dirs.config_dir().join("parameters.toml"))
+}
+
+// The parameters from the end of the last run, if there was one.
+pub fn load_saved() -> Result<Option<Parameters>, Box<dyn Error>> {
+    match saved_path() {
+        Some(path) if path.exists() => Ok(Some(load(&path)?)),
+        _ => Ok(None),
+    }
+}
+
+// Saves the parameters when quitting, so the next run starts where this one left off.
+pub fn save_on_exit(params: Res<Parameters>, mut exit: EventReader<AppExit>) {
+    if exit.read().next().is_none() {
+        return;
+    }
+    let Some(path) = saved_path() else {
+        return;
+    };
+    let result = path
+        .parent()
+        .map_or(Ok(()), fs::create_dir_all)
+        .map_err(Into::into)
+        .and_then(|()| save(&params, &path));
+    if let Err(e) = result {
+        error!("Failed to save parameters to {}: {}", path.display(), e);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::SPECIES_HUES;
+
+    #[test]
+    fn presets_with_too_few_or_too_many_species_load() {
+        let mut params = Parameters {
+            number_of_species: 0,
+            ..Default::default()
+        };
+        params.species.truncate(1);
+        let params = parse(&toml::to_string(&params).unwrap()).unwrap();
+        assert_eq!(params.number_of_species, 1);
+        assert_eq!(params.species.len(), SPECIES_HUES.len());
+
+        let mut params = Parameters {
+            number_of_species: SPECIES_HUES.len() + 1,
+            ..Default::default()
+        };
+        params.species.clear();
+        let params = parse(&toml::to_string(&params).unwrap()).unwrap();
+        assert_eq!(params.number_of_species, SPECIES_HUES.len());
+        assert_eq!(params.species.len(), SPECIES_HUES.len());
+    }
+
+    #[test]
+    fn saved_parameters_missing_fields_load_with_the_defaults() {
+        let params = parse("number_of_boids = 100\nnumber_of_species = 3\n").unwrap();
+        assert_eq!(params.number_of_boids, 100);
+        assert_eq!(params.number_of_species, 3);
+        assert_eq!(params.species.len(), SPECIES_HUES.len());
+        let expected = Parameters {
+            number_of_boids: 100,
+            number_of_species: 3,
+            seed: params.seed,
+            ..Default::default()
+        };
+        assert!(params == expected);
+    }
+}
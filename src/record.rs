@@ -0,0 +1,69 @@
+// Recording the trajectories of the boids to a CSV file for offline analysis.
+
+use std::{
+    fs::File,
+    io::{self, BufWriter, Write},
+    path::Path,
+};
+
+use bevy::{app::AppExit, prelude::*};
+
+use crate::Boid;
+
+// How many frames to buffer before writing them out.
+const FLUSH_INTERVAL: u64 = 60;
+
+#[derive(Resource)]
+pub struct Recorder {
+    writer: BufWriter<File>,
+    frame: u64,
+}
+
+impl Recorder {
+    pub fn create(path: &Path) -> io::Result<Self> {
+        let mut writer = BufWriter::new(File::create(path)?);
+        writeln!(writer, "frame,entity,x,y,vx,vy,species")?;
+        Ok(Self { writer, frame: 0 })
+    }
+
+    fn write_frame<'a>(
+        &mut self,
+        boids: impl Iterator<Item = (Entity, &'a Transform, &'a Boid)>,
+    ) -> io::Result<()> {
+        for (e, t, boid) in boids {
+            writeln!(
+                self.writer,
+                "{},{},{},{},{},{},{}",
+                self.frame,
+                e.to_bits(),
+                t.translation.x,
+                t.translation.y,
+                boid.velocity.x,
+                boid.velocity.y,
+                boid.species
+            )?;
+        }
+        self.frame += 1;
+        if self.frame.is_multiple_of(FLUSH_INTERVAL) {
+            self.writer.flush()?;
+        }
+        Ok(())
+    }
+}
+
+// Appends one row per boid for the current frame. Runs after the boids have moved.
+pub fn record(
+    mut commands: Commands,
+    mut recorder: ResMut<Recorder>,
+    mut exit: EventReader<AppExit>,
+    query: Query<(Entity, &Transform, &Boid)>,
+) {
+    let mut result = recorder.write_frame(query.iter());
+    if result.is_ok() && exit.read().next().is_some() {
+        result = recorder.writer.flush();
+    }
+    if let Err(e) = result {
+        error!("Stopped recording: {}", e);
+        commands.remove_resource::<Recorder>();
+    }
+}
@@ -0,0 +1,205 @@
+// Recording runs to RON files and replaying them. The simulation is deterministic given its seed
+// and parameters, so a replay only keeps the parameters it started from and how they changed,
+// not where the boids went. This holds whatever the frame rate, as boids are only added and
+// removed as part of a simulation step, see add_simulation(). Mouse clicks, keyboard shortcuts and
+// respawning aren't recorded, and the background flocking isn't deterministic.
+
+use std::{
+    collections::VecDeque,
+    error::Error,
+    fs,
+    path::{Path, PathBuf},
+};
+
+use bevy::{app::AppExit, prelude::*};
+use serde::{Deserialize, Serialize};
+
+use crate::Parameters;
+
+#[derive(Serialize, Deserialize)]
+pub struct Replay {
+    // These include the seed.
+    pub parameters: Parameters,
+    changes: VecDeque<Change>,
+}
+
+// The parameters from a given simulation step on.
+#[derive(Serialize, Deserialize)]
+struct Change {
+    step: u64,
+    parameters: Parameters,
+}
+
+pub fn load(path: &Path) -> Result<Replay, Box<dyn Error>> {
+    Ok(ron::from_str(&fs::read_to_string(path)?)?)
+}
+
+// Writes the replay of the current run to a file when the app exits.
+#[derive(Resource)]
+pub struct ReplayRecorder {
+    path: PathBuf,
+    replay: Replay,
+    step: u64,
+}
+
+impl ReplayRecorder {
+    pub fn new(path: PathBuf, parameters: Parameters) -> Self {
+        Self {
+            path,
+            replay: Replay {
+                parameters,
+                changes: VecDeque::new(),
+            },
+            step: 0,
+        }
+    }
+
+    fn last(&self) -> &Parameters {
+        self.replay
+            .changes
+            .back()
+            .map_or(&self.replay.parameters, |change| &change.parameters)
+    }
+}
+
+// Notes any change to the parameters before each simulation step.
+pub fn record(params: Res<Parameters>, mut recorder: ResMut<ReplayRecorder>) {
+    if *recorder.last() != *params {
+        let step = recorder.step;
+        recorder.replay.changes.push_back(Change {
+            step,
+            parameters: params.clone(),
+        });
+    }
+    recorder.step += 1;
+}
+
+pub fn save_on_exit(recorder: Res<ReplayRecorder>, mut exit: EventReader<AppExit>) {
+    if exit.read().next().is_none() {
+        return;
+    }
+    let result = ron::ser::to_string_pretty(&recorder.replay, default())
+        .map_err(Box::<dyn Error>::from)
+        .and_then(|ron| Ok(fs::write(&recorder.path, ron)?));
+    match result {
+        Ok(()) => info!("Saved replay to {}", recorder.path.display()),
+        Err(e) => error!(
+            "Failed to save replay to {}: {}",
+            recorder.path.display(),
+            e
+        ),
+    }
+}
+
+// Plays back the parameter changes of a replay, at the same steps they were recorded at.
+#[derive(Resource)]
+pub struct ReplayPlayer {
+    changes: VecDeque<Change>,
+    step: u64,
+}
+
+impl ReplayPlayer {
+    pub fn new(replay: Replay) -> Self {
+        Self {
+            changes: replay.changes,
+            step: 0,
+        }
+    }
+}
+
+pub fn play(mut params: ResMut<Parameters>, mut player: ResMut<ReplayPlayer>) {
+    while player
+        .changes
+        .front()
+        .is_some_and(|change| change.step <= player.step)
+    {
+        if let Some(change) = player.changes.pop_front() {
+            *params = change.parameters;
+        }
+    }
+    player.step += 1;
+}
+
+#[cfg(test)]
+mod tests {
+    use bevy::{time::TimeUpdateStrategy, window::PrimaryWindow};
+
+    use super::*;
+    use crate::{add_simulation, RngResource, SpawnIndex};
+
+    // Plays the replay for the given number of simulation steps, taking as many steps every
+    // frame, and returns where each boid ends up and how fast it's going then.
+    fn play_back(replay: Replay, steps: u64, steps_per_frame: u32) -> Vec<(u64, Vec3, Vec2)> {
+        let mut app = App::new();
+        let time = Time::<Fixed>::from_hz(60.0);
+        app.add_plugins(MinimalPlugins)
+            .insert_resource(TimeUpdateStrategy::ManualDuration(
+                time.timestep() * steps_per_frame,
+            ))
+            .insert_resource(time)
+            .init_resource::<Assets<Mesh>>()
+            .init_resource::<Assets<ColorMaterial>>()
+            .init_resource::<ButtonInput<MouseButton>>()
+            .init_resource::<ButtonInput<KeyCode>>()
+            .insert_resource(RngResource::new(replay.parameters.seed))
+            .insert_resource(replay.parameters.clone())
+            .insert_resource(ReplayPlayer::new(replay));
+        add_simulation(&mut app).add_systems(FixedUpdate, play.before(crate::respawn));
+        // The mouse is looked for, but not over the window.
+        app.world.spawn((Window::default(), PrimaryWindow));
+        app.world.spawn(Camera2dBundle::default());
+        while app.world.resource::<ReplayPlayer>().step < steps {
+            app.update();
+        }
+        assert_eq!(app.world.resource::<ReplayPlayer>().step, steps);
+
+        let mut boids: Vec<(u64, Vec3, Vec2)> = app
+            .world
+            .query::<(&SpawnIndex, &Transform, &crate::Boid)>()
+            .iter(&app.world)
+            .map(|(i, t, b)| (i.0, t.translation, b.velocity))
+            .collect();
+        boids.sort_unstable_by_key(|&(i, ..)| i);
+        boids
+    }
+
+    fn replay() -> Replay {
+        let parameters = Parameters {
+            world_width: 400.0,
+            world_height: 300.0,
+            number_of_boids: 40,
+            seed: 7,
+            ..default()
+        };
+        let change = |step, parameters| Change { step, parameters };
+        Replay {
+            changes: VecDeque::from([
+                change(
+                    30,
+                    Parameters {
+                        number_of_boids: 100,
+                        number_of_predators: 1,
+                        ..parameters.clone()
+                    },
+                ),
+                change(
+                    90,
+                    Parameters {
+                        number_of_boids: 20,
+                        number_of_species: 3,
+                        ..parameters.clone()
+                    },
+                ),
+            ]),
+            parameters,
+        }
+    }
+
+    #[test]
+    fn replays_are_the_same_whatever_the_frame_rate() {
+        let boids = play_back(replay(), 180, 1);
+        assert_eq!(boids.len(), 20);
+        assert_eq!(play_back(replay(), 180, 1), boids);
+        assert_eq!(play_back(replay(), 180, 3), boids);
+    }
+}
@@ -0,0 +1,43 @@
+// Scenarios describing how a run starts, loaded from RON files.
+
+#[cfg(not(target_arch = "wasm32"))]
+use std::{error::Error, fs, path::Path};
+
+use bevy::prelude::*;
+use serde::Deserialize;
+
+// A circle, unless it has half extents, in which case it's a rectangle.
+#[derive(Deserialize)]
+pub struct ObstacleDescription {
+    pub position: Vec2,
+    #[serde(default)]
+    pub radius: f32,
+    #[serde(default)]
+    pub half_extents: Option<Vec2>,
+}
+
+// For example:
+//
+// (
+//     number_of_boids: Some(512),
+//     obstacles: [
+//         (position: (0.0, 0.0), radius: 40.0),
+//         (position: (0.0, 200.0), half_extents: Some((150.0, 10.0))),
+//     ],
+//     waypoints: [(-200.0, 100.0), (200.0, 100.0), (0.0, -150.0)],
+// )
+#[derive(Resource, Deserialize)]
+pub struct Scenario {
+    #[serde(default)]
+    pub number_of_boids: Option<usize>,
+    #[serde(default)]
+    pub obstacles: Vec<ObstacleDescription>,
+    // The flock heads to each of these in turn, looping back to the first one.
+    #[serde(default)]
+    pub waypoints: Vec<Vec2>,
+}
+
+#[cfg(not(target_arch = "wasm32"))]
+pub fn load(path: &Path) -> Result<Scenario, Box<dyn Error>> {
+    Ok(ron::from_str(&fs::read_to_string(path)?)?)
+}
@@ -0,0 +1,86 @@
+// Saving and loading layouts of obstacles and predators as Bevy scenes.
+
+use std::{error::Error, fs, path::Path};
+
+use bevy::{ecs::entity::EntityHashMap, prelude::*, scene::serde::SceneDeserializer};
+use serde::de::DeserializeSeed;
+
+use crate::{presets::file_dialog, Obstacle, Parameters, Predator, SceneRequest};
+
+pub fn save(world: &mut World, path: &Path) -> Result<(), Box<dyn Error>> {
+    let entities: Vec<Entity> = world
+        .query_filtered::<Entity, Or<(With<Obstacle>, With<Predator>)>>()
+        .iter(world)
+        .collect();
+    let scene = DynamicSceneBuilder::from_world(world)
+        .deny_all()
+        .allow::<Obstacle>()
+        .allow::<Predator>()
+        .allow::<Transform>()
+        .extract_entities(entities.into_iter())
+        .build();
+    fs::write(
+        path,
+        scene.serialize_ron(world.resource::<AppTypeRegistry>())?,
+    )?;
+    Ok(())
+}
+
+// Replaces the obstacles and predators with those of the scene.
+pub fn load(world: &mut World, path: &Path) -> Result<(), Box<dyn Error>> {
+    let text = fs::read_to_string(path)?;
+    let scene = {
+        let registry = world.resource::<AppTypeRegistry>().read();
+        let mut deserializer = ron::de::Deserializer::from_str(&text)?;
+        SceneDeserializer {
+            type_registry: &registry,
+        }
+        .deserialize(&mut deserializer)?
+    };
+
+    let existing: Vec<Entity> = world
+        .query_filtered::<Entity, Or<(With<Obstacle>, With<Predator>)>>()
+        .iter(world)
+        .collect();
+    for e in existing {
+        world.despawn(e);
+    }
+    scene.write_to_world(world, &mut EntityHashMap::default())?;
+
+    // Keep the loaded predators instead of adding or removing some to match the parameters.
+    let predators = world.query::<&Predator>().iter(world).count();
+    world.resource_mut::<Parameters>().number_of_predators = predators;
+    Ok(())
+}
+
+// Asks where to save or what to load when requested from the parameters window. This needs the
+// whole world, so it can't happen from there.
+pub fn handle_requests(world: &mut World) {
+    let requests: Vec<SceneRequest> = world
+        .resource_mut::<Events<SceneRequest>>()
+        .drain()
+        .collect();
+    for request in requests {
+        let dialog = file_dialog("Scene", "ron");
+        match request {
+            SceneRequest::Save => {
+                let Some(path) = dialog.set_file_name("layout.ron").save_file() else {
+                    continue; // Cancelled.
+                };
+                match save(world, &path) {
+                    Ok(()) => info!("Saved layout to {}", path.display()),
+                    Err(e) => error!("Failed to save layout to {}: {}", path.display(), e),
+                }
+            }
+            SceneRequest::Load => {
+                let Some(path) = dialog.pick_file() else {
+                    continue;
+                };
+                match load(world, &path) {
+                    Ok(()) => info!("Loaded layout from {}", path.display()),
+                    Err(e) => error!("Failed to load layout from {}: {}", path.display(), e),
+                }
+            }
+        }
+    }
+}
@@ -0,0 +1,298 @@
+// A 3D version of the simulation, started with --3d. It follows the same parameters and flocking
+// rules as the 2D one, but the boids fly inside a box instead of the window, and there are no
+// obstacles, predators or parameters window yet. The flocking also leaves out:
+// - the field of view and perception fidelity, so boids see all around them;
+// - leaders and weights, so all boids count the same and weighted cohesion changes nothing;
+// - the pull to the center and wandering;
+// - a direction for boids at the exact same position to separate in;
+// - the boundary modes, as boids always bounce off the sides of the box.
+
+use bevy::{
+    input::mouse::{MouseMotion, MouseScrollUnit, MouseWheel},
+    prelude::*,
+    render::{mesh::PrimitiveTopology, render_asset::RenderAssetUsages},
+    utils::HashMap,
+};
+use rand::Rng;
+
+use crate::{flocking, AlignmentMode, Parameters, RngResource, SPECIES_HUES};
+
+#[derive(Component)]
+struct Boid3d {
+    velocity: Vec3,
+    species: u8,
+}
+
+// The camera circles around the center of the box, looking at it.
+#[derive(Component)]
+struct OrbitCamera {
+    yaw: f32,
+    pitch: f32,
+    distance: f32,
+}
+
+pub fn run(params: Parameters) {
+    App::new()
+        .add_plugins(DefaultPlugins.set(WindowPlugin {
+            primary_window: Some(Window {
+                resolution: (params.world_width, params.world_height).into(),
+                ..default()
+            }),
+            ..default()
+        }))
+        .insert_resource(RngResource::new(params.seed))
+        .insert_resource(params)
+        .add_systems(Startup, setup)
+        // There's nothing to interpolate the boids with, so they move once per frame.
+        .add_systems(Update, (flock, fly, orbit_camera, draw_box).chain())
+        .run();
+}
+
+// Half the size of the box the boids fly in. It's as deep as it's high.
+fn half_extents(params: &Parameters) -> Vec3 {
+    Vec3::new(params.world_width, params.world_height, params.world_height) / 2.0
+}
+
+// A pyramid pointing up, like the triangle of the 2D boids.
+fn boid_mesh() -> Mesh {
+    let tip = [0.0, 8.0, 0.0];
+    let base = [
+        [-3.0, -4.0, -3.0],
+        [3.0, -4.0, -3.0],
+        [3.0, -4.0, 3.0],
+        [-3.0, -4.0, 3.0],
+    ];
+    let mut positions = Vec::new();
+    for i in 0..4 {
+        positions.extend([base[(i + 1) % 4], base[i], tip]);
+    }
+    positions.extend([base[0], base[1], base[2], base[0], base[2], base[3]]);
+
+    let mut mesh = Mesh::new(
+        PrimitiveTopology::TriangleList,
+        RenderAssetUsages::default(),
+    )
+    .with_inserted_attribute(Mesh::ATTRIBUTE_POSITION, positions);
+    mesh.compute_flat_normals();
+    mesh
+}
+
+fn setup(
+    mut commands: Commands,
+    params: Res<Parameters>,
+    mut rng: ResMut<RngResource>,
+    mut meshes: ResMut<Assets<Mesh>>,
+    mut materials: ResMut<Assets<StandardMaterial>>,
+) {
+    let half = half_extents(&params);
+    commands.spawn((
+        Camera3dBundle::default(),
+        OrbitCamera {
+            yaw: 0.0,
+            pitch: 0.3,
+            distance: half.length() * 2.0,
+        },
+    ));
+    commands.spawn(DirectionalLightBundle {
+        transform: Transform::from_xyz(1.0, 2.0, 1.5).looking_at(Vec3::ZERO, Vec3::Y),
+        ..default()
+    });
+
+    let mesh = meshes.add(boid_mesh());
+    let materials: Vec<Handle<StandardMaterial>> = (0..params.number_of_species)
+        .map(|species| {
+            materials.add(Color::hsl(
+                SPECIES_HUES[species],
+                params.boid_saturation,
+                params.boid_lightness,
+            ))
+        })
+        .collect();
+
+    let rng = &mut rng.0;
+    for _ in 0..params.number_of_boids {
+        let species = rng.gen_range(0..params.number_of_species);
+        let position = Vec3::new(
+            rng.gen_range(-half.x..half.x),
+            rng.gen_range(-half.y..half.y),
+            rng.gen_range(-half.z..half.z),
+        );
+        let direction = Vec3::new(
+            rng.gen_range(-1.0..1.0),
+            rng.gen_range(-1.0..1.0),
+            rng.gen_range(-1.0..1.0),
+        )
+        .try_normalize()
+        .unwrap_or(Vec3::Y);
+        let speeds = params.species(species as u8);
+        let velocity = direction * rng.gen_range(speeds.min_speed..=speeds.max_speed);
+        commands.spawn((
+            PbrBundle {
+                mesh: mesh.clone(),
+                material: materials[species].clone(),
+                transform: Transform::from_translation(position)
+                    .with_rotation(Quat::from_rotation_arc(Vec3::Y, direction)),
+                ..default()
+            },
+            Boid3d {
+                velocity,
+                species: species as u8,
+            },
+        ));
+    }
+}
+
+// The same cohesion, separation and alignment as perceive() and forces() in 2D, with a grid of
+// cubes as large as the view distance to find the neighbours.
+fn flock(params: Res<Parameters>, mut query: Query<(Entity, &Transform, &mut Boid3d)>) {
+    let boids: Vec<(Entity, Vec3, Vec3, u8)> = query
+        .iter()
+        .map(|(e, t, b)| (e, t.translation, b.velocity, b.species))
+        .collect();
+    let cell_size = params.view_distance.max(1.0);
+    let cell = |position: Vec3| (position / cell_size).floor().as_ivec3();
+    let mut grid: HashMap<IVec3, Vec<usize>> = HashMap::new();
+    for (i, &(_, position, _, _)) in boids.iter().enumerate() {
+        grid.entry(cell(position)).or_default().push(i);
+    }
+
+    let steering =
+        |direction: Vec3, force: f32| force * direction.clamp_length_max(params.steering_force);
+    for (i, &(e1, p1, v1, s1)) in boids.iter().enumerate() {
+        let (mut cohesion, mut separation, mut alignment) = (Vec3::ZERO, Vec3::ZERO, Vec3::ZERO);
+        let mut cohesion_neighbours = 0;
+        let c1 = cell(p1);
+        for x in -1..=1 {
+            for y in -1..=1 {
+                for z in -1..=1 {
+                    let Some(others) = grid.get(&(c1 + IVec3::new(x, y, z))) else {
+                        continue;
+                    };
+                    for &j in others {
+                        let (_, p2, v2, s2) = boids[j];
+                        let offset = p1 - p2;
+                        let distance = offset.length();
+                        if j == i || distance > params.view_distance {
+                            continue;
+                        }
+                        // Boids of all species keep apart, but only flock with their own kind.
+                        if distance < params.separation_distance {
+                            let closing_speed =
+                                -offset.dot(v1 - v2) / distance.max(flocking::MIN_DISTANCE);
+                            separation += offset
+                                * flocking::separation_factor(
+                                    distance,
+                                    params.separation_distance,
+                                    params.separation_curve,
+                                    params.separation_bias,
+                                )
+                                * flocking::closing_factor(
+                                    closing_speed,
+                                    v1.length() + v2.length(),
+                                    params.separation_velocity_bias,
+                                );
+                        }
+                        if s1 != s2 {
+                            continue;
+                        }
+                        if distance <= params.cohesion_distance {
+                            cohesion += p2 + v2 * params.prediction_time;
+                            cohesion_neighbours += 1;
+                        }
+                        let min_speed_squared =
+                            params.alignment_min_speed * params.alignment_min_speed;
+                        if distance <= params.alignment_distance
+                            && v1.length_squared() >= min_speed_squared
+                            && v2.length_squared() >= min_speed_squared
+                        {
+                            let alignment_factor =
+                                flocking::alignment_factor_3d(v1, v2, params.alignment_bias);
+                            alignment += match params.alignment_mode {
+                                AlignmentMode::SummedVelocity => v2,
+                                AlignmentMode::AverageHeading => v2.normalize_or_zero(),
+                            } * alignment_factor;
+                        }
+                    }
+                }
+            }
+        }
+        if cohesion_neighbours > 0 {
+            cohesion = cohesion / cohesion_neighbours as f32 - p1;
+        }
+
+        let species = params.species(s1);
+        let velocity = v1
+            + steering(cohesion, species.cohesion_force)
+            + steering(separation, species.separation_force)
+            + steering(alignment, species.alignment_force);
+        // Without a heading there's no way to speed up to the minimum speed, as in
+        // flocking::combine().
+        if velocity == Vec3::ZERO {
+            continue;
+        }
+        let velocity = velocity.clamp_length(species.min_speed, species.max_speed);
+        if let Ok((_, _, mut boid)) = query.get_mut(e1) {
+            boid.velocity = velocity;
+        }
+    }
+}
+
+// Moves the boids, bouncing them off the sides of the box.
+fn fly(time: Res<Time>, params: Res<Parameters>, mut query: Query<(&mut Transform, &mut Boid3d)>) {
+    let half = half_extents(&params);
+    for (mut t, mut boid) in &mut query {
+        t.translation += boid.velocity * params.speed_multiplier * time.delta_seconds();
+        for axis in 0..3 {
+            if t.translation[axis].abs() > half[axis] {
+                t.translation[axis] = t.translation[axis].clamp(-half[axis], half[axis]);
+                boid.velocity[axis] = -boid.velocity[axis].abs() * t.translation[axis].signum();
+            }
+        }
+        if let Some(direction) = boid.velocity.try_normalize() {
+            t.rotation = Quat::from_rotation_arc(Vec3::Y, direction);
+        }
+    }
+}
+
+// How far the camera can get from the center, as a multiple of the size of the box.
+const MIN_DISTANCE: f32 = 0.1;
+const MAX_DISTANCE: f32 = 10.0;
+
+// Dragging with the left mouse button orbits around the box, the mouse wheel zooms in and out.
+fn orbit_camera(
+    params: Res<Parameters>,
+    buttons: Res<ButtonInput<MouseButton>>,
+    mut wheel: EventReader<MouseWheel>,
+    mut motion: EventReader<MouseMotion>,
+    mut camera: Query<(&mut Transform, &mut OrbitCamera)>,
+) {
+    let (mut transform, mut orbit) = camera.single_mut();
+    let size = half_extents(&params).length();
+    for event in wheel.read() {
+        let lines = match event.unit {
+            MouseScrollUnit::Line => event.y,
+            MouseScrollUnit::Pixel => event.y / 100.0,
+        };
+        orbit.distance =
+            (orbit.distance * (1.0 - lines * 0.1)).clamp(MIN_DISTANCE * size, MAX_DISTANCE * size);
+    }
+
+    let drag: Vec2 = motion.read().map(|event| event.delta).sum();
+    if buttons.pressed(MouseButton::Left) {
+        orbit.yaw -= drag.x * 0.005;
+        // Stop short of straight up or down, where looking at the center flips the camera.
+        let limit = std::f32::consts::FRAC_PI_2 - 0.01;
+        orbit.pitch = (orbit.pitch + drag.y * 0.005).clamp(-limit, limit);
+    }
+
+    let rotation = Quat::from_euler(EulerRot::YXZ, orbit.yaw, -orbit.pitch, 0.0);
+    *transform = Transform::from_translation(rotation * Vec3::Z * orbit.distance)
+        .looking_at(Vec3::ZERO, Vec3::Y);
+}
+
+fn draw_box(mut gizmos: Gizmos, params: Res<Parameters>) {
+    gizmos.cuboid(
+        Transform::from_scale(half_extents(&params) * 2.0),
+        Color::GRAY,
+    );
+}